@@ -4,6 +4,7 @@ mod modules;
 mod transpilers;
 use anyhow::Error;
 use anyhow::Result;
+use base64::prelude::*;
 
 use modules::load_import;
 use modules::resolve_import;
@@ -12,6 +13,8 @@ pub use modules::CORE_MODULES;
 use std::collections::HashMap;
 use swc_bundler::ModuleType;
 
+use path_absolutize::Absolutize;
+use sourcemap::SourceMapBuilder;
 use std::path::Path;
 use swc_atoms::Atom;
 use swc_bundler::Bundler;
@@ -24,9 +27,11 @@ use swc_common::errors::ColorConfig;
 use swc_common::errors::Handler;
 use swc_common::source_map::SourceMap;
 use swc_common::sync::Lrc;
+use swc_common::BytePos;
 use swc_common::FileName;
 use swc_common::FilePathMapping;
 use swc_common::Globals;
+use swc_common::LineCol;
 use swc_common::Span;
 use swc_ecma_ast::*;
 use swc_ecma_codegen::text_writer::JsWriter;
@@ -36,6 +41,17 @@ use swc_ecma_parser::parse_file_as_module;
 use swc_ecma_parser::EsSyntax;
 use swc_ecma_parser::Syntax;
 
+// Prefix of the inline source maps added by the transpilers.
+const INLINE_SOURCE_MAP: &str = "//# sourceMappingURL=data:application/json;base64,";
+
+// The bundled code and its source map, pointing at the files the bundle was built from,
+// relative to the directory of the entry.
+#[derive(Debug)]
+pub struct Bundle {
+    pub code: String,
+    pub source_map: String,
+}
+
 #[derive(Debug)]
 pub struct Options {
     pub skip_cache: bool,
@@ -54,7 +70,7 @@ impl Default for Options {
     }
 }
 
-pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
+pub fn run_bundle(entry: &str, options: &Options) -> Result<Bundle> {
     // Create SWC globals and an LRC sourcemap.
     let globals = Globals::default();
     let cm = Lrc::new(SourceMap::new(FilePathMapping::empty()));
@@ -97,6 +113,7 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
         .unwrap();
 
     let mut buf = vec![];
+    let mut mappings = vec![];
 
     {
         let mut cfg = swc_ecma_codegen::Config::default();
@@ -106,7 +123,12 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
             cfg,
             cm: cm.clone(),
             comments: None,
-            wr: Box::new(JsWriter::new(cm, "\n", &mut buf, None)),
+            wr: Box::new(JsWriter::new(
+                cm.clone(),
+                "\n",
+                &mut buf,
+                Some(&mut mappings),
+            )),
         };
 
         emitter.emit_module(&bundle.module)?;
@@ -114,6 +136,7 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
 
     // Build source from bytes.
     let mut source = String::from_utf8(buf).unwrap();
+    let mut header_lines = 0;
 
     if !options.minify {
         // Decorate output with the following messages.
@@ -123,10 +146,83 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
         ];
         messages.iter().rev().for_each(|msg| {
             source.insert_str(0, msg);
+            header_lines += msg.matches('\n').count() as u32;
         });
     }
 
-    Ok(source)
+    let root = Path::new(entry).absolutize()?;
+    let root = root.parent().unwrap_or(Path::new(""));
+    let source_map = compose_source_map(&cm, &mappings, root, header_lines)?;
+
+    Ok(Bundle {
+        code: source,
+        source_map,
+    })
+}
+
+// Maps the bundle to the files the loader read, then through the inline source maps of the
+// transpiled ones, so the positions end up in the original .ts files.
+fn compose_source_map(
+    cm: &Lrc<SourceMap>,
+    mappings: &[(BytePos, LineCol)],
+    root: &Path,
+    line_offset: u32,
+) -> Result<String> {
+    let bundle_map = cm.build_source_map(mappings);
+    let mut inline_maps = HashMap::new();
+    let mut builder = SourceMapBuilder::new(None);
+
+    for token in bundle_map.tokens() {
+        let Some(source) = token.get_source() else {
+            continue;
+        };
+        let inline_map = inline_maps
+            .entry(source.to_string())
+            .or_insert_with(|| inline_source_map(cm, source));
+        let (source, line, col, name) = match inline_map {
+            Some(map) => match map.lookup_token(token.get_src_line(), token.get_src_col()) {
+                Some(original) => (
+                    original.get_source().unwrap_or(source),
+                    original.get_src_line(),
+                    original.get_src_col(),
+                    original.get_name().or(token.get_name()),
+                ),
+                None => continue,
+            },
+            None => (
+                source,
+                token.get_src_line(),
+                token.get_src_col(),
+                token.get_name(),
+            ),
+        };
+        let path = Path::new(source).absolutize()?;
+        let source = path
+            .strip_prefix(root)
+            .map(|path| path.to_string_lossy())
+            .unwrap_or(source.into());
+        builder.add(
+            token.get_dst_line() + line_offset,
+            token.get_dst_col(),
+            line,
+            col,
+            Some(&source),
+            name,
+            false,
+        );
+    }
+
+    let mut buffer = Vec::new();
+    builder.into_sourcemap().to_writer(&mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+// Returns the source map a transpiler inlined into a loaded file, if any.
+fn inline_source_map(cm: &Lrc<SourceMap>, source: &str) -> Option<sourcemap::SourceMap> {
+    let file = cm.get_source_file(&FileName::Real(source.into()))?;
+    let (_, encoded) = file.src.rsplit_once(INLINE_SOURCE_MAP)?;
+    let decoded = BASE64_STANDARD.decode(encoded.trim()).ok()?;
+    sourcemap::SourceMap::from_slice(&decoded).ok()
 }
 
 struct Loader<'s> {
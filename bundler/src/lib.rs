@@ -1,5 +1,5 @@
 mod bundle;
-pub use bundle::run_bundle;
+pub use bundle::{run_bundle, Bundle};
 
 #[cfg(test)]
 mod tests {
@@ -10,7 +10,28 @@ mod tests {
     fn bundle_ts_should_work() -> Result<()> {
         let ret = run_bundle("fixtures/main.ts", &Default::default())?;
 
-        assert_eq!(ret, "(function(){async function execute(name){console.log(\"Executing lib\");return`Hello ${name}!`;}async function main(){console.log(\"Executing main\");const result=await execute(\"world\");console.log(result);}return{main:main};})();");
+        assert_eq!(ret.code, "(function(){async function execute(name){console.log(\"Executing lib\");return`Hello ${name}!`;}async function main(){console.log(\"Executing main\");const result=await execute(\"world\");console.log(result);}return{main:main};})();");
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_source_map_should_work() -> Result<()> {
+        let ret = run_bundle("fixtures/main.ts", &Default::default())?;
+        let map = sourcemap::SourceMap::from_slice(ret.source_map.as_bytes())?;
+
+        let lookup = |needle: &str| {
+            let col = ret.code.find(needle).unwrap() as u32;
+            let token = map.lookup_token(0, col).unwrap();
+            (
+                token.get_source().unwrap().to_string(),
+                token.get_src_line(),
+            )
+        };
+        assert_eq!(
+            lookup("console.log(\"Executing main\")"),
+            ("main.ts".into(), 2)
+        );
+        assert_eq!(lookup("return`Hello"), ("lib.ts".into(), 2));
         Ok(())
     }
 }
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use dino_server::{ProjectConfig, SwappableAppRouter, TenentRouter};
+use dino_server::{ProjectConfig, ServerOptions, SwappableAppRouter, TenentRouter};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{
     fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
@@ -28,15 +28,15 @@ async fn main() -> Result<()> {
         "#;
     // router.insert(
     //     "localhost".to_string(),
-    //     SwappableAppRouter::new(code.to_string(), config.routes)?,
+    //     SwappableAppRouter::new(code.to_string(), config)?,
     // );
 
     let router = vec![TenentRouter::new(
         "localhost".to_string(),
-        SwappableAppRouter::new(code.to_string(), config.routes)?,
+        SwappableAppRouter::new(code.to_string(), config)?,
     )];
 
     // let router = Arc::new(router);
-    dino_server::start_server(8080, router).await?;
+    dino_server::start_server(8080, router, ServerOptions::default()).await?;
     Ok(())
 }
@@ -0,0 +1,400 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use typed_builder::TypedBuilder;
+
+use crate::{metrics::metrics_response, AppError, AppState, JsBundle, ProjectConfig};
+
+//bundles are uploaded whole, well above what a project is expected to weigh
+const MAX_UPLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct AdminOptions {
+    pub port: u16,
+    //expected as `Authorization: Bearer <token>` on every request
+    #[builder(setter(into))]
+    pub token: String,
+    //loopback by default, the admin api shouldn't be reachable from outside unless asked to
+    #[builder(default = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub ip: IpAddr,
+}
+
+#[derive(Debug, Serialize)]
+struct Tenant {
+    host: String,
+    hash: String,
+}
+
+#[derive(Clone)]
+struct AdminState {
+    app: AppState,
+    token: String,
+}
+
+//serves the admin api until the server starts draining
+pub(crate) async fn serve(listener: TcpListener, state: AppState, token: String) {
+    let drain = state.drain.clone();
+    let ret = axum::serve(listener, app(state, token))
+        .with_graceful_shutdown(async move {
+            drain.started().await;
+        })
+        .await;
+    if let Err(e) = ret {
+        warn!("Admin api stopped: {}", e);
+    }
+}
+
+fn app(app: AppState, token: String) -> Router {
+    let state = AdminState { app, token };
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/tenants", get(list_tenants))
+        .route("/tenants/:host", put(upload_tenant).delete(delete_tenant))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+async fn authorize(
+    State(state): State<AdminState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), state.token.as_bytes()) => {
+            Ok(next.run(req).await)
+        }
+        _ => Err(AppError::Unauthorized),
+    }
+}
+
+async fn metrics(State(state): State<AdminState>) -> impl IntoResponse {
+    metrics_response(&state.app)
+}
+
+async fn list_tenants(State(state): State<AdminState>) -> Json<Vec<Tenant>> {
+    let mut tenants: Vec<_> = state
+        .app
+        .tenants
+        .hosts
+        .iter()
+        .map(|entry| Tenant {
+            host: entry.key().clone(),
+            hash: entry.value().load().hash.clone(),
+        })
+        .collect();
+    tenants.sort_by(|a, b| a.host.cmp(&b.host));
+    Json(tenants)
+}
+
+//multipart with a `bundle` field holding the built code, a `config` field holding config.yml and
+//an optional `source_map` one holding the map of the bundle, an existing tenant is swapped to the
+//new code, otherwise it is created, the hosts of the config are served by it too
+async fn upload_tenant(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    if state.app.drain.is_draining() {
+        return Err(AppError::ShuttingDown);
+    }
+    let (mut code, mut config, mut source_map) = (None, None, None);
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::InvalidBundle(e.body_text()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let text = field
+            .text()
+            .await
+            .map_err(|e| AppError::InvalidBundle(e.body_text()))?;
+        match name.as_str() {
+            "bundle" => code = Some(text),
+            "config" => config = Some(text),
+            "source_map" => source_map = Some(text),
+            _ => warn!(tenant = %host, "Unknown field {} in upload, ignored", name),
+        }
+    }
+    let code = code.ok_or_else(|| AppError::InvalidBundle("missing bundle field".to_string()))?;
+    let config =
+        config.ok_or_else(|| AppError::InvalidBundle("missing config field".to_string()))?;
+    let config: ProjectConfig = serde_yaml::from_str(&config)
+        .map_err(|e| AppError::InvalidBundle(format!("config.yml: {}", e)))?;
+
+    let bundle = JsBundle { code, source_map };
+    bundle
+        .check(&config)
+        .await
+        .map_err(|e| AppError::InvalidBundle(format!("{:#}", e)))?;
+    let created = state
+        .app
+        .tenants
+        .deploy(&host, bundle, config)
+        .map_err(|e| AppError::InvalidBundle(format!("{:#}", e)))?;
+    let status = match created {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    let hash = state.app.tenants.get(&host).map(|r| r.load().hash.clone());
+    info!(tenant = %host, hash = ?hash, "Tenant deployed");
+    let tenant = Tenant {
+        host,
+        hash: hash.unwrap_or_default(),
+    };
+    Ok((status, Json(tenant)).into_response())
+}
+
+async fn delete_tenant(
+    State(state): State<AdminState>,
+    Path(host): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let router = state
+        .app
+        .remove(&host)
+        .ok_or_else(|| AppError::HostNotFound(host.clone()))?;
+    //the hosts of its config go with it
+    for alias in state.app.tenants.hosts_of(&router) {
+        state.app.remove(&alias);
+    }
+    info!(tenant = %host, "Tenant removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+//compares every byte so the time taken doesn't tell how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt as _;
+
+    const TOKEN: &str = "secret";
+    const CONFIG: &str = r#"
+name: dino-test
+routes:
+  /api/hello:
+    - method: GET
+      handler: hello
+"#;
+
+    fn code(msg: &str) -> String {
+        format!(
+            "(function(){{ async function hello(req){{ return {{ status: 200, headers: {{}}, body: '{}' }}; }} return {{ hello }}; }})();",
+            msg
+        )
+    }
+
+    fn upload(host: &str, code: &str, config: &str) -> Request<Body> {
+        let boundary = "dino-boundary";
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"bundle\"; filename=\"bundle.mjs\"\r\n\r\n{code}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"config\"; filename=\"config.yml\"\r\n\r\n{config}\r\n\
+             --{b}--\r\n",
+            b = boundary,
+        );
+        Request::put(format!("/tenants/{}", host))
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn json(res: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_should_require_token() {
+        let admin = app(AppState::default(), TOKEN.to_string());
+        let req = Request::get("/tenants").body(Body::empty()).unwrap();
+        let res = admin.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let req = Request::get("/tenants")
+            .header(header::AUTHORIZATION, "Bearer secreT")
+            .body(Body::empty())
+            .unwrap();
+        let res = admin.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = admin.oneshot(request("GET", "/tenants")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_should_deploy_and_remove_tenants() {
+        let state = AppState::default();
+        let admin = app(state.clone(), TOKEN.to_string());
+
+        let res = admin
+            .clone()
+            .oneshot(upload("localhost", &code("v1"), CONFIG))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let v1 = json(res).await["hash"].as_str().unwrap().to_string();
+        assert_eq!(v1, crate::build_hash(&code("v1")));
+
+        let res = admin
+            .clone()
+            .oneshot(upload("localhost", &code("v2"), CONFIG))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let v2 = json(res).await["hash"].as_str().unwrap().to_string();
+        assert_ne!(v1, v2);
+
+        let res = admin
+            .clone()
+            .oneshot(request("GET", "/tenants"))
+            .await
+            .unwrap();
+        let tenants = json(res).await;
+        assert_eq!(
+            tenants,
+            serde_json::json!([{ "host": "localhost", "hash": v2 }])
+        );
+        let router = state.tenants.get("localhost").unwrap().load();
+        let req = crate::Req::builder()
+            .method("GET")
+            .url("/api/hello")
+            .build();
+        let res = router
+            .pool
+            .run("hello", req, std::time::Duration::from_secs(1))
+            .await
+            .unwrap();
+        let Some(crate::ResBody::Bytes(body)) = res.body else {
+            panic!("hello should return a body");
+        };
+        assert_eq!(body.0, "v2");
+
+        let res = admin
+            .clone()
+            .oneshot(request("DELETE", "/tenants/localhost"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(state.tenants.is_empty());
+        let res = admin
+            .oneshot(request("DELETE", "/tenants/localhost"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn admin_should_reject_invalid_bundle() {
+        let state = AppState::default();
+        let admin = app(state.clone(), TOKEN.to_string());
+        let res = admin
+            .oneshot(upload("localhost", &code("v1"), "routes: [1, 2]"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(state.tenants.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_should_reject_bundle_without_handlers() {
+        let state = AppState::default();
+        let admin = app(state.clone(), TOKEN.to_string());
+        let res = admin
+            .clone()
+            .oneshot(upload("localhost", &code("v1"), CONFIG))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        for code in [
+            "(function(){ return { hi: async () => ({}) }; })();",
+            "(function(){ throw new Error('broken'); })();",
+        ] {
+            let res = admin
+                .clone()
+                .oneshot(upload("localhost", code, CONFIG))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+        let hash = state.tenants.get("localhost").unwrap().load().hash.clone();
+        assert_eq!(hash, crate::build_hash(&code("v1")));
+    }
+
+    #[tokio::test]
+    async fn admin_should_serve_config_hosts() {
+        let state = AppState::default();
+        let admin = app(state.clone(), TOKEN.to_string());
+        let config = format!("hosts: [www.shop.test, '*.shop.test']\n{}", CONFIG);
+        let res = admin
+            .clone()
+            .oneshot(upload("shop.test", &code("v1"), &config))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let shop = state.tenants.get("shop.test").unwrap();
+        let alice = state.tenants.resolve("alice.shop.test").unwrap();
+        assert!(std::sync::Arc::ptr_eq(&shop.inners, &alice.inners));
+
+        //another tenant can't take the hosts of the first one
+        let res = admin
+            .clone()
+            .oneshot(upload("blog.test", &code("v1"), &config))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(state.tenants.get("blog.test").is_none());
+
+        let config = format!("hosts: [www.shop.test]\n{}", CONFIG);
+        let res = admin
+            .clone()
+            .oneshot(upload("shop.test", &code("v2"), &config))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.tenants.resolve("alice.shop.test").is_none());
+        assert!(state.tenants.get("www.shop.test").is_some());
+
+        let res = admin
+            .oneshot(request("DELETE", "/tenants/shop.test"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(state.tenants.is_empty());
+    }
+}
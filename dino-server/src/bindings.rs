@@ -0,0 +1,244 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use rquickjs::{
+    function::{Opt, Rest},
+    Array, Ctx, Function, Object, Value,
+};
+use tracing::warn;
+
+//installs `process.binding(name)` and the global timer functions on top of the bindings
+const PRELUDE: &str = r#"
+(function (bindings) {
+    globalThis.process = globalThis.process || {};
+    process.binding = function (name) {
+        if (!(name in bindings)) {
+            throw new Error(`No such binding: ${name}`);
+        }
+        return bindings[name];
+    };
+
+    const TIMEOUT_MAX = Math.pow(2, 31) - 1;
+    const timers = bindings.timers;
+    const delayOf = (delay) => {
+        delay *= 1;
+        return delay >= 1 && delay <= TIMEOUT_MAX ? delay : 1;
+    };
+
+    globalThis.setTimeout = (callback, delay, ...args) =>
+        timers.createTimeout(callback, delayOf(delay), false, args);
+    globalThis.setInterval = (callback, delay, ...args) =>
+        timers.createTimeout(callback, delayOf(delay), true, args);
+    globalThis.setImmediate = (callback, ...args) =>
+        timers.createImmediate(() => callback(...args));
+    globalThis.clearTimeout = (id) => timers.removeTimeout(id);
+    globalThis.clearInterval = (id) => timers.removeTimeout(id);
+    globalThis.clearImmediate = (id) => timers.removeImmediate(id);
+})
+"#;
+
+pub(crate) fn init_bindings(ctx: &Ctx) -> Result<()> {
+    let bindings = Object::new(ctx.clone())?;
+    bindings.set("timers", timers_binding(ctx)?)?;
+
+    let prelude: Function = ctx.eval(PRELUDE)?;
+    prelude.call::<_, ()>((bindings,))?;
+    Ok(())
+}
+
+//timers are futures spawned on the js runtime, they fire while the runtime is driven by a handler
+#[derive(Clone, Default)]
+struct Timers {
+    next_id: Rc<Cell<u64>>,
+    active: Rc<RefCell<HashSet<u64>>>,
+}
+
+impl Timers {
+    fn add(&self) -> u64 {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        self.active.borrow_mut().insert(id);
+        id
+    }
+
+    fn is_active(&self, id: u64) -> bool {
+        self.active.borrow().contains(&id)
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        self.active.borrow_mut().remove(&id)
+    }
+}
+
+fn timers_binding<'js>(ctx: &Ctx<'js>) -> Result<Object<'js>> {
+    let timers = Timers::default();
+    let binding = Object::new(ctx.clone())?;
+
+    let state = timers.clone();
+    binding.set(
+        "createTimeout",
+        Function::new(
+            ctx.clone(),
+            move |ctx: Ctx<'js>,
+                  callback: Function<'js>,
+                  delay: f64,
+                  repeat: bool,
+                  args: Opt<Array<'js>>| {
+                let id = state.add();
+                let state = state.clone();
+                let delay = Duration::from_millis(delay.max(0.0) as u64);
+                let args = args
+                    .0
+                    .map(|args| args.iter::<Value>().collect::<rquickjs::Result<Vec<_>>>())
+                    .transpose()?
+                    .unwrap_or_default();
+                let inner = ctx.clone();
+                ctx.spawn(async move {
+                    loop {
+                        tokio::time::sleep(delay).await;
+                        if !state.is_active(id) {
+                            break;
+                        }
+                        if !repeat {
+                            state.remove(id);
+                        }
+                        call_timer(&inner, &callback, args.clone());
+                        if !repeat {
+                            break;
+                        }
+                    }
+                });
+                Ok::<_, rquickjs::Error>(id)
+            },
+        )?,
+    )?;
+
+    let state = timers.clone();
+    binding.set(
+        "createImmediate",
+        Function::new(
+            ctx.clone(),
+            move |ctx: Ctx<'js>, callback: Function<'js>| {
+                let id = state.add();
+                let state = state.clone();
+                let inner = ctx.clone();
+                ctx.spawn(async move {
+                    if state.remove(id) {
+                        call_timer(&inner, &callback, Vec::new());
+                    }
+                });
+                id
+            },
+        )?,
+    )?;
+
+    let state = timers.clone();
+    binding.set(
+        "removeTimeout",
+        Function::new(ctx.clone(), move |id: u64| {
+            state.remove(id);
+        })?,
+    )?;
+
+    let state = timers;
+    binding.set(
+        "removeImmediate",
+        Function::new(ctx.clone(), move |id: u64| {
+            state.remove(id);
+        })?,
+    )?;
+
+    Ok(binding)
+}
+
+//a throwing timer callback can't reject anything, so the error is only logged
+fn call_timer<'js>(ctx: &Ctx<'js>, callback: &Function<'js>, args: Vec<Value<'js>>) {
+    if let Err(e) = callback.call::<_, ()>((Rest(args),)) {
+        let detail = match e {
+            rquickjs::Error::Exception => format!("{:?}", ctx.catch()),
+            e => e.to_string(),
+        };
+        warn!("Timer callback failed: {}", detail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{AppError, JsEngine, Req};
+
+    const CODE: &str = r#"
+        (function(){
+            async function sleep(req){
+                await new Promise((resolve) => setTimeout(resolve, 20));
+                let ticks = 0;
+                await new Promise((resolve) => {
+                    const id = setInterval((step) => {
+                        ticks += step;
+                        if (ticks === 3) {
+                            clearInterval(id);
+                            resolve();
+                        }
+                    }, 5, 1);
+                });
+                const cancelled = setTimeout(() => { ticks = -1; }, 1);
+                clearTimeout(cancelled);
+                await new Promise((resolve) => setImmediate(resolve));
+                await new Promise((resolve) => setTimeout(resolve, 10));
+                return { status: 200, headers: {}, body: String(ticks) };
+            }
+            async function binding(req){
+                const timers = process.binding("timers");
+                let body = typeof timers.createTimeout;
+                try {
+                    process.binding("fs");
+                } catch (e) {
+                    body += " " + e.message;
+                }
+                return { status: 200, headers: {}, body };
+            }
+            async function slow(req){
+                await new Promise((resolve) => setTimeout(resolve, 1000));
+                return { status: 200, headers: {}, body: "done" };
+            }
+            return { sleep, binding, slow };
+        })();
+    "#;
+
+    fn req() -> Req {
+        Req::builder().method("GET").url("/").build()
+    }
+
+    #[tokio::test]
+    async fn timers_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let res = engine
+            .run("sleep", req(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.body, Some("3".into()));
+    }
+
+    #[tokio::test]
+    async fn process_binding_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let res = engine
+            .run("binding", req(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.body, Some("function No such binding: fs".into()));
+    }
+
+    #[tokio::test]
+    async fn pending_timer_should_timeout() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let ret = engine.run("slow", req(), Duration::from_millis(50)).await;
+        assert!(matches!(ret, Err(AppError::HandlerTimeout(_, _))));
+    }
+}
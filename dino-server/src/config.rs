@@ -1,20 +1,90 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
 use crate::ProjectRouters;
 use anyhow::Result;
 use axum::http::Method;
+use croner::Cron;
 use serde::Deserialize;
 
+//used when neither the route nor the project defines a timeout
+pub const DEFAULT_HANDLER_TIMEOUT: Duration = Duration::from_secs(30);
+//number of requests allowed to wait for a busy js engine
+pub const DEFAULT_QUEUE_SIZE: usize = 128;
+//how long a request waits for a free slot in the queue before giving up
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Deserialize)]
 pub struct ProjectConfig {
     pub name: String,
+    //hosts the project is served on, `*.example.com` matches any single label in front of
+    //example.com, exact hosts win over wildcards
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    //project level default timeout for all handlers
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub limits: RuntimeLimits,
+    #[serde(default)]
+    pub pool: PoolConfig,
     pub routes: ProjectRouters,
+    #[serde(default)]
+    pub schedules: Vec<ProjectSchedule>,
+}
+
+//a handler invoked on a cron schedule (UTC), with the payload as its json request body
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProjectSchedule {
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub cron: Cron,
+    pub handler: String,
+    #[serde(default)]
+    pub payload: Option<serde_json::Value>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+}
+
+//js engine threads of the project and the queue in front of them
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct PoolConfig {
+    pub workers: Option<usize>,
+    pub queue_size: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub wait_timeout: Option<Duration>,
+}
+
+//resource limits applied to every quickjs runtime of the project, unset means quickjs default
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct RuntimeLimits {
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub memory: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub stack_size: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub gc_threshold: Option<usize>,
 }
 #[derive(Debug, Deserialize)]
 pub struct ProjectRoute {
     #[serde(deserialize_with = "deserialize_method")]
     pub method: Method,
     pub handler: String,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub kind: RouteKind,
+}
+
+//how the handler talks to the client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteKind {
+    //the handler gets the request and returns a response
+    #[default]
+    Http,
+    //the handler gets the request and an event sink, the connection stays open for server-sent events
+    Sse,
+    //the handler is an object with `onOpen`, `onMessage` and `onClose`, called for each websocket
+    Ws,
 }
 
 impl ProjectConfig {
@@ -43,3 +113,202 @@ where
         _ => Err(serde::de::Error::custom("Invalid method")),
     }
 }
+
+//standard 5 fields cron expression, an optional leading seconds field is accepted
+fn deserialize_cron<'de, D>(deserializer: D) -> Result<Cron, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    Cron::new(&s)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| serde::de::Error::custom(format!("Invalid cron expression {s}: {e}")))
+}
+
+//accept either a number of milliseconds or a string like "500ms", "5s" or "1m"
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Millis(u64),
+        Text(String),
+    }
+
+    match Option::<RawDuration>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawDuration::Millis(ms)) => Ok(Some(Duration::from_millis(ms))),
+        Some(RawDuration::Text(s)) => parse_duration(&s)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid duration: {s}"))),
+    }
+}
+
+//accept either a number of bytes or a string like "512KB", "64MB" or "1GB"
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawSize {
+        Bytes(usize),
+        Text(String),
+    }
+
+    match Option::<RawSize>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawSize::Bytes(n)) => Ok(Some(n)),
+        Some(RawSize::Text(s)) => parse_size(&s)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid size: {s}"))),
+    }
+}
+
+fn split_number(s: &str) -> Option<(u64, &str)> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    Some((num.parse().ok()?, unit.trim()))
+}
+
+fn parse_size(s: &str) -> Option<usize> {
+    let (num, unit) = split_number(s)?;
+    let unit: u64 = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    num.checked_mul(unit)?.try_into().ok()
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (num, unit) = split_number(s)?;
+    match unit {
+        "" | "ms" => Some(Duration::from_millis(num)),
+        "s" => Some(Duration::from_secs(num)),
+        "m" => Some(Duration::from_secs(num.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_config_timeout_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.timeout, Some(Duration::from_secs(5)));
+        let routes = &config.routes["/api/hello/:id"];
+        assert_eq!(routes[0].timeout, Some(Duration::from_millis(500)));
+        assert_eq!(routes[1].timeout, None);
+    }
+
+    #[test]
+    fn project_config_limits_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.limits.memory, Some(64 << 20));
+        assert_eq!(config.limits.stack_size, Some(512 << 10));
+        assert_eq!(config.limits.gc_threshold, Some(1 << 20));
+
+        let config = include_str!("../fixtures/config1.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.limits.memory, None);
+    }
+
+    #[test]
+    fn project_config_hosts_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.hosts, ["localhost", "*.dino.localhost"]);
+
+        let config = include_str!("../fixtures/config1.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert!(config.hosts.is_empty());
+    }
+
+    #[test]
+    fn project_config_pool_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.pool.workers, Some(2));
+        assert_eq!(config.pool.queue_size, Some(16));
+        assert_eq!(config.pool.wait_timeout, Some(Duration::from_millis(200)));
+
+        let config = include_str!("../fixtures/config1.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.pool.workers, None);
+    }
+
+    #[test]
+    fn project_config_route_kind_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.routes["/api/hello/:id"][0].kind, RouteKind::Http);
+        assert_eq!(config.routes["/api/events"][0].kind, RouteKind::Sse);
+        assert_eq!(config.routes["/ws/:room"][0].kind, RouteKind::Ws);
+    }
+
+    #[test]
+    fn project_config_schedules_should_work() {
+        let config = r#"
+            name: dino-test
+            routes: {}
+            schedules:
+              - cron: "0 3 * * *"
+                handler: cleanup
+                payload:
+                  days: 30
+              - cron: "*/10 * * * * *"
+                handler: warm
+                timeout: 2s
+        "#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert_eq!(config.schedules.len(), 2);
+        assert_eq!(config.schedules[0].handler, "cleanup");
+        assert_eq!(
+            config.schedules[0].payload,
+            Some(serde_json::json!({"days": 30}))
+        );
+        assert_eq!(config.schedules[1].timeout, Some(Duration::from_secs(2)));
+
+        let config = include_str!("../fixtures/config1.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert!(config.schedules.is_empty());
+
+        let config = r#"
+            name: dino-test
+            routes: {}
+            schedules:
+              - cron: "every night"
+                handler: cleanup
+        "#;
+        assert!(serde_yaml::from_str::<ProjectConfig>(config).is_err());
+    }
+
+    #[test]
+    fn parse_size_should_work() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("2KB"), Some(2048));
+        assert_eq!(parse_size("64mb"), Some(64 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("1TB"), None);
+    }
+
+    #[test]
+    fn parse_duration_should_work() {
+        assert_eq!(parse_duration("100"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_duration("100ms"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_duration("3s"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("2h"), None);
+        assert_eq!(parse_duration("s"), None);
+    }
+}
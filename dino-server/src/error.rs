@@ -1,7 +1,10 @@
+use std::{fmt, time::Duration};
+
 use axum::{
-    http::{Method, StatusCode},
+    http::{header, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
 };
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,24 +15,235 @@ pub enum AppError {
     AnyhowError(#[from] anyhow::Error),
     #[error("Path not found: {0}")]
     RouterPathNotFound(String),
+    //the second field is the value of the Allow header
     #[error("Method not found: {0}")]
-    RouterMethodNotAllow(Method),
+    RouterMethodNotAllow(Method, String),
     #[error("Serde json error: {0}")]
     SerderError(#[from] serde_json::Error),
-    #[error("Js error: {0}")]
-    JsError(String),
+    #[error("{0}")]
+    JsError(JsException),
+    #[error("Handler {0} timed out after {1:?}")]
+    HandlerTimeout(String, Duration),
+    #[error("Handler {0} ran out of memory")]
+    JsOutOfMemory(String),
+    #[error("Handler {0} exceeded the max stack size")]
+    JsStackOverflow(String),
+    #[error("Too many pending requests, retry after {0:?}")]
+    QueueFull(Duration),
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+}
+
+//what a handler threw, an error object or any other value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsException {
+    pub handler: String,
+    //`TypeError`, `Error`..., empty if the value thrown is not an error
+    pub name: String,
+    pub message: String,
+    pub stack: Option<String>,
+}
+
+impl fmt::Display for JsException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.as_str() {
+            "" => write!(f, "Handler {} threw {}", self.handler, self.message),
+            name => write!(
+                f,
+                "Handler {} threw {}: {}",
+                self.handler, name, self.message
+            ),
+        }
+    }
+}
+
+const PROBLEM_JSON: &str = "application/problem+json";
+//the type of a problem is `urn:dino:error:<code>`
+const PROBLEM_TYPE_PREFIX: &str = "urn:dino:error:";
+
+//how much an error response tells about what went wrong
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorDetail {
+    //the messages of the tenant code and the server internals are withheld
+    #[default]
+    Prod,
+    //every message is sent as is, error chains and js stacks included, for development
+    Verbose,
+}
+
+//what an AppError response is rendered from, kept in the response extensions so the problem
+//layer can render it again with the request id, the tenant and the detail configured
+#[derive(Debug, Clone)]
+pub(crate) struct Problem {
+    code: &'static str,
+    title: &'static str,
+    status: StatusCode,
+    detail: String,
+    //the detail can be sent in prod mode
+    public: bool,
+    //of a js error, only sent in verbose mode
+    stack: Option<String>,
+}
+
+//RFC 9457, `code`, `tenant` and `request_id` are extension members
+#[derive(Debug, Serialize)]
+struct ProblemBody<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    code: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+impl Problem {
+    pub(crate) fn body(
+        &self,
+        detail: ErrorDetail,
+        tenant: Option<&str>,
+        request_id: Option<&str>,
+    ) -> String {
+        let body = ProblemBody {
+            kind: format!("{}{}", PROBLEM_TYPE_PREFIX, self.code),
+            title: self.title,
+            status: self.status.as_u16(),
+            detail: match detail {
+                ErrorDetail::Verbose => Some(&self.detail),
+                ErrorDetail::Prod => self.public.then_some(&*self.detail),
+            },
+            code: self.code,
+            stack: match detail {
+                ErrorDetail::Verbose => self.stack.as_deref(),
+                ErrorDetail::Prod => None,
+            },
+            tenant,
+            request_id,
+        };
+        serde_json::to_string(&body).unwrap_or_default()
+    }
 }
 
 impl IntoResponse for AppError {
+    //rendered in prod mode, the problem layer adds what it knows about the request
     fn into_response(self) -> Response {
-        let code = match self {
-            AppError::HostNotFound(_) => StatusCode::NOT_FOUND,
-            AppError::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::RouterPathNotFound(_) => StatusCode::NOT_FOUND,
-            AppError::RouterMethodNotAllow(_) => StatusCode::METHOD_NOT_ALLOWED,
-            AppError::SerderError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::JsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        let problem = self.problem();
+        let headers = [(header::CONTENT_TYPE, PROBLEM_JSON)];
+        let body = problem.body(ErrorDetail::Prod, None, None);
+        let mut res = (problem.status, headers, body).into_response();
+        let headers = res.headers_mut();
+        match self {
+            AppError::RouterMethodNotAllow(_, allow) => {
+                if let Ok(allow) = HeaderValue::from_str(&allow) {
+                    headers.insert(header::ALLOW, allow);
+                }
+            }
+            AppError::QueueFull(retry_after) => {
+                headers.insert(header::RETRY_AFTER, retry_after.as_secs().into());
+            }
+            //the client should take its next requests to another instance
+            AppError::ShuttingDown => {
+                headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+            }
+            AppError::Unauthorized => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
+        res.extensions_mut().insert(problem);
+        res
+    }
+}
+
+impl AppError {
+    //stable identifier of the variant, the `code` of the problem and a metrics label
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::HostNotFound(_) => "host_not_found",
+            AppError::AnyhowError(_) => "internal",
+            AppError::RouterPathNotFound(_) => "path_not_found",
+            AppError::RouterMethodNotAllow(_, _) => "method_not_allowed",
+            AppError::SerderError(_) => "serde_error",
+            AppError::JsError(_) => "js_error",
+            AppError::HandlerTimeout(_, _) => "handler_timeout",
+            AppError::JsOutOfMemory(_) => "js_out_of_memory",
+            AppError::JsStackOverflow(_) => "js_stack_overflow",
+            AppError::QueueFull(_) => "queue_full",
+            AppError::ShuttingDown => "shutting_down",
+            AppError::Unauthorized => "unauthorized",
+            AppError::InvalidBundle(_) => "invalid_bundle",
+        }
+    }
+
+    fn problem(&self) -> Problem {
+        let (status, title, public) = match self {
+            AppError::HostNotFound(_) => (StatusCode::NOT_FOUND, "Host not found", true),
+            AppError::AnyhowError(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error",
+                false,
+            ),
+            AppError::RouterPathNotFound(_) => (StatusCode::NOT_FOUND, "Path not found", true),
+            AppError::RouterMethodNotAllow(_, _) => {
+                (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed", true)
+            }
+            AppError::SerderError(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error",
+                false,
+            ),
+            AppError::JsError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Handler failed", false),
+            AppError::HandlerTimeout(_, _) => {
+                (StatusCode::GATEWAY_TIMEOUT, "Handler timed out", true)
+            }
+            AppError::JsOutOfMemory(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Handler ran out of memory",
+                true,
+            ),
+            AppError::JsStackOverflow(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Handler exceeded the max stack size",
+                true,
+            ),
+            AppError::QueueFull(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many pending requests",
+                true,
+            ),
+            AppError::ShuttingDown => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Server is shutting down",
+                true,
+            ),
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized", true),
+            AppError::InvalidBundle(_) => (StatusCode::BAD_REQUEST, "Invalid bundle", true),
+        };
+        let detail = match self {
+            AppError::AnyhowError(e) => format!("{:#}", e),
+            e => e.to_string(),
+        };
+        let stack = match self {
+            AppError::JsError(e) => e.stack.clone(),
+            _ => None,
         };
-        (code, self.to_string()).into_response()
+        Problem {
+            code: self.code(),
+            title,
+            status,
+            detail,
+            public,
+            stack,
+        }
     }
 }
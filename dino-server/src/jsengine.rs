@@ -1,18 +1,83 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use axum::{body::Body, response::Response};
+use axum::{
+    body::{Body, Bytes},
+    http::header,
+    response::{IntoResponse, Response},
+};
 use dino_macros::{FromJs, IntoJs};
-use rquickjs::{Context, Function, Object, Promise, Runtime};
+use rquickjs::{
+    async_with,
+    convert::Coerced,
+    function::{Opt, This},
+    AsyncContext, AsyncRuntime, Ctx, FromJs, Function, IntoJs, Object, Promise, TypedArray, Value,
+};
+use tracing::warn;
 use typed_builder::TypedBuilder;
 
+use crate::{
+    bindings::init_bindings,
+    sse::event_sink,
+    stream::{js_error_message, Streams},
+    timing,
+    ws::open_socket,
+    AppError, JsException, ResBody, RouteKind, RuntimeLimits, ServerTiming,
+};
+
+//the exceptions thrown by quickjs itself when it runs out of memory or stack, a handler throwing
+//an error with the same message but of another kind is only a js error
+const OUT_OF_MEMORY: (&str, &str) = ("InternalError", "out of memory");
+const STACK_OVERFLOW: (&str, &str) = ("RangeError", "Maximum call stack size exceeded");
+
+//how a handler is called and what its result turns into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HandlerKind {
+    Http,
+    Sse,
+    Ws,
+    Schedule,
+}
+
+impl From<RouteKind> for HandlerKind {
+    fn from(kind: RouteKind) -> Self {
+        match kind {
+            RouteKind::Http => Self::Http,
+            RouteKind::Sse => Self::Sse,
+            RouteKind::Ws => Self::Ws,
+        }
+    }
+}
+
 #[allow(unused)]
 #[derive(Clone)]
 pub struct JsEngine {
-    pub rt: Runtime,
-    pub ctx: Context,
+    pub rt: AsyncRuntime,
+    pub ctx: AsyncContext,
+    interrupt: Interrupt,
+    //the same as the context userdata, to tell whether streams are open without locking it
+    streams: Streams,
+    memory_limited: bool,
 }
+
+//shared with the quickjs interrupt handler, which aborts the running js once a deadline passed,
+//a handler and the streams left open by the previous ones may each have one armed
+#[derive(Clone, Default)]
+pub(crate) struct Interrupt(Rc<RefCell<Deadlines>>);
+
+#[derive(Default)]
+struct Deadlines {
+    next_id: u64,
+    //deadline and whether the js was interrupted because of it
+    armed: HashMap<u64, (Instant, bool)>,
+}
+
 fn print(msg: String) {
     println!("{msg}");
 }
@@ -21,12 +86,19 @@ fn print(msg: String) {
 pub struct Req {
     #[builder(default)]
     pub headers: HashMap<String, String>,
+    //exposed as an Uint8Array, `req.text()` gives the utf-8 decoded body
     #[builder(default)]
-    pub body: Option<String>,
+    pub body: Option<JsBody>,
     #[builder(setter(into))]
     pub method: String,
     #[builder(setter(into))]
     pub url: String,
+    //path prefix the tenant is mounted under, empty when it is reached by its host
+    #[builder(default, setter(into))]
+    pub mount: String,
+    //id of the request, the one in the x-request-id header of the response
+    #[builder(default, setter(into))]
+    pub id: String,
     #[builder(default)]
     pub query: HashMap<String, String>,
     #[builder(default)]
@@ -36,37 +108,160 @@ pub struct Req {
 #[derive(Debug, FromJs, serde::Serialize)]
 pub struct Res {
     pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    //a string, an Uint8Array or an ArrayBuffer sent as is,
+    //or a ReadableStream or an async iterable of those streamed chunk by chunk
+    pub body: Option<ResBody>,
     pub status: u16,
 }
 
+//raw bytes of a request or response body
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JsBody(pub Bytes);
+
+impl<T: Into<Bytes>> From<T> for JsBody {
+    fn from(bytes: T) -> Self {
+        Self(bytes.into())
+    }
+}
+
+impl<'js> IntoJs<'js> for JsBody {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        TypedArray::<u8>::new_copy(ctx.clone(), &self.0)?.into_js(ctx)
+    }
+}
+
+impl<'js> FromJs<'js> for JsBody {
+    fn from_js(_ctx: &Ctx<'js>, value: Value<'js>) -> rquickjs::Result<Self> {
+        if let Some(s) = value.as_string() {
+            return Ok(s.to_string()?.into());
+        }
+        if let Some(obj) = value.as_object() {
+            let bytes = if let Some(buf) = obj.as_array_buffer() {
+                buf.as_bytes()
+            } else if let Some(arr) = obj.as_typed_array::<u8>() {
+                arr.as_bytes()
+            } else {
+                None
+            };
+            if let Some(bytes) = bytes {
+                return Ok(Bytes::copy_from_slice(bytes).into());
+            }
+        }
+        Err(rquickjs::Error::new_from_js(
+            value.type_name(),
+            "string, Uint8Array or ArrayBuffer",
+        ))
+    }
+}
+
+impl serde::Serialize for JsBody {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+//a status or a header the js got wrong is a server error, not a panic of the worker
 impl From<Res> for Response {
     fn from(res: Res) -> Self {
         let mut builder = Response::builder().status(res.status);
         for (k, v) in res.headers {
             builder = builder.header(k, v);
         }
+        if let (Some(ResBody::Events(_)), Some(headers)) = (&res.body, builder.headers_mut()) {
+            headers
+                .entry(header::CONTENT_TYPE)
+                .or_insert_with(|| header::HeaderValue::from_static("text/event-stream"));
+            headers
+                .entry(header::CACHE_CONTROL)
+                .or_insert_with(|| header::HeaderValue::from_static("no-cache"));
+        }
+
+        let body = res.body.map(Body::from).unwrap_or_else(Body::empty);
+        builder.body(body).unwrap_or_else(|e| {
+            AppError::AnyhowError(anyhow!("Invalid handler response: {}", e)).into_response()
+        })
+    }
+}
+
+impl Interrupt {
+    async fn install(&self, rt: &AsyncRuntime) {
+        let interrupt = self.clone();
+        rt.set_interrupt_handler(Some(Box::new(move || interrupt.check())))
+            .await;
+    }
+
+    fn check(&self) -> bool {
+        let now = Instant::now();
+        let mut deadlines = self.0.borrow_mut();
+        let mut expired = false;
+        for (deadline, triggered) in deadlines.armed.values_mut() {
+            if now >= *deadline {
+                *triggered = true;
+                expired = true;
+            }
+        }
+        expired
+    }
 
-        if let Some(body) = res.body {
-            builder.body(body.into()).unwrap()
-        } else {
-            builder.body(Body::empty()).unwrap()
+    //the id is given back to `disarm` once the js is done
+    pub(crate) fn arm(&self, timeout: Duration) -> u64 {
+        let mut deadlines = self.0.borrow_mut();
+        let id = deadlines.next_id;
+        deadlines.next_id += 1;
+        //a deadline too far to be represented never comes
+        if let Some(deadline) = Instant::now().checked_add(timeout) {
+            deadlines.armed.insert(id, (deadline, false));
         }
+        id
+    }
+
+    //returns true if the js code was interrupted because of this deadline
+    pub(crate) fn disarm(&self, id: u64) -> bool {
+        let mut deadlines = self.0.borrow_mut();
+        deadlines
+            .armed
+            .remove(&id)
+            .is_some_and(|(_, triggered)| triggered)
     }
 }
 
 impl JsEngine {
     pub async fn init() -> Result<Self> {
-        let rt = Runtime::new()?;
-        let ctx = Context::full(&rt)?;
-        Ok(Self { rt, ctx })
+        let rt = AsyncRuntime::new()?;
+        let ctx = AsyncContext::full(&rt).await?;
+        let interrupt = Interrupt::default();
+        interrupt.install(&rt).await;
+        Ok(Self {
+            rt,
+            ctx,
+            streams: Streams::new(interrupt.clone()),
+            interrupt,
+            memory_limited: false,
+        })
     }
-    pub fn new(module: &str) -> Result<Self> {
+    pub async fn new(module: &str, limits: &RuntimeLimits) -> Result<Self> {
         //using rquickjs for set js global object and run js code
-        let rt = Runtime::new()?;
-        let ctx = Context::full(&rt)?;
+        let rt = AsyncRuntime::new()?;
+        if let Some(memory) = limits.memory {
+            rt.set_memory_limit(memory).await;
+        }
+        if let Some(stack_size) = limits.stack_size {
+            rt.set_max_stack_size(stack_size).await;
+        }
+        if let Some(gc_threshold) = limits.gc_threshold {
+            rt.set_gc_threshold(gc_threshold).await;
+        }
+        let ctx = AsyncContext::full(&rt).await?;
+        let interrupt = Interrupt::default();
+        interrupt.install(&rt).await;
 
-        ctx.with(|ctx| {
+        let module = module.to_string();
+        let streams = Streams::new(interrupt.clone());
+        let userdata = streams.clone();
+        async_with!(ctx => |ctx| {
+            ctx.store_userdata(userdata)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            init_bindings(&ctx)?;
             let global = ctx.globals();
             let module: Object = ctx.eval(module)?;
             global.set("handlers", module)?;
@@ -75,18 +270,444 @@ impl JsEngine {
                 Function::new(ctx.clone(), print)?.with_name("print")?,
             )?;
             Ok::<_, anyhow::Error>(())
-        })?;
+        })
+        .await?;
 
-        Ok(Self { rt, ctx })
+        Ok(Self {
+            rt,
+            ctx,
+            interrupt,
+            streams,
+            memory_limited: limits.memory.is_some(),
+        })
+    }
+
+    //the handler's promise, its pending jobs and timers are driven on the current tokio runtime,
+    //timers left behind by a handler only make progress while the engine runs a handler
+    pub async fn run(&self, name: &str, req: Req, timeout: Duration) -> Result<Res, AppError> {
+        self.invoke(HandlerKind::Http, name, req, timeout).await
+    }
+
+    //the sse handler is called with the request and an event sink, the response is sent right away
+    //and the events follow while the engine drains its streams
+    pub async fn run_sse(&self, name: &str, req: Req, timeout: Duration) -> Result<Res, AppError> {
+        self.invoke(HandlerKind::Sse, name, req, timeout).await
+    }
+
+    //`onOpen` is called within the timeout, the socket is then driven while the engine drains its
+    //streams, each `onMessage` and `onClose` call gets the same timeout
+    pub async fn run_ws(&self, name: &str, req: Req, timeout: Duration) -> Result<Res, AppError> {
+        self.invoke(HandlerKind::Ws, name, req, timeout).await
+    }
+
+    //a scheduled handler may resolve to nothing, which is reported as 204
+    pub async fn run_schedule(
+        &self,
+        name: &str,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        self.invoke(HandlerKind::Schedule, name, req, timeout).await
+    }
+
+    pub(crate) async fn invoke(
+        &self,
+        kind: HandlerKind,
+        name: &str,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        let deadline = self.interrupt.arm(timeout);
+        let ret = async_with!(self.ctx => |ctx| {
+            if let Some(streams) = ctx.userdata::<Streams>() {
+                streams.set_timeout(timeout);
+            }
+            let call = async {
+                match kind {
+                    HandlerKind::Http => call_handler(&ctx, name, req).await,
+                    HandlerKind::Sse => call_sse_handler(&ctx, name, req),
+                    HandlerKind::Ws => call_ws_handler(&ctx, name, req).await,
+                    HandlerKind::Schedule => call_scheduled_handler(&ctx, name, req).await,
+                }
+            };
+            match tokio::time::timeout(timeout, call).await {
+                Ok(ret) => ret.map_err(|e| self.map_js_error(&ctx, name, e)),
+                Err(_) => Err(AppError::HandlerTimeout(name.to_string(), timeout)),
+            }
+        })
+        .await;
+        if self.interrupt.disarm(deadline) {
+            return Err(AppError::HandlerTimeout(name.to_string(), timeout));
+        }
+        ret
     }
-    pub fn run(&self, name: &str, req: Req) -> Result<Res> {
-        self.ctx.with(|ctx| {
-            let global = ctx.globals();
-            let handlers = global.get::<_, Object>("handlers")?;
-            let function = handlers.get::<_, Function>(name)?;
-            let result: Promise = function.call((req,))?;
 
-            Ok::<_, anyhow::Error>(result.finish()?)
+    //calls an optional lifecycle hook exported by the module, returns false if there is none
+    pub async fn run_hook(&self, name: &str, timeout: Duration) -> Result<bool, AppError> {
+        let deadline = self.interrupt.arm(timeout);
+        let ret = async_with!(self.ctx => |ctx| {
+            match tokio::time::timeout(timeout, call_hook(&ctx, name)).await {
+                Ok(ret) => ret.map_err(|e| self.map_js_error(&ctx, name, e)),
+                Err(_) => Err(AppError::HandlerTimeout(name.to_string(), timeout)),
+            }
         })
+        .await;
+        if self.interrupt.disarm(deadline) {
+            return Err(AppError::HandlerTimeout(name.to_string(), timeout));
+        }
+        ret
+    }
+
+    //the handlers the module doesn't export, a websocket handler is an object, any other a function
+    pub(crate) async fn missing_handlers(&self, handlers: &[(String, HandlerKind)]) -> Vec<String> {
+        async_with!(self.ctx => |ctx| {
+            let exports = ctx.globals().get::<_, Object>("handlers").ok();
+            handlers
+                .iter()
+                .filter(|(name, kind)| {
+                    let value = exports.as_ref().and_then(|e| e.get::<_, Value>(name.as_str()).ok());
+                    !value.is_some_and(|v| match kind {
+                        HandlerKind::Ws => v.is_object(),
+                        _ => v.is_function(),
+                    })
+                })
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+        .await
+    }
+
+    pub(crate) fn has_streams(&self) -> bool {
+        self.streams.is_active()
+    }
+
+    //drives the runtime until the streams, event sinks and sockets left open are finished
+    pub async fn drain_streams(&self) {
+        async_with!(self.ctx => |ctx| {
+            let streams = ctx.userdata::<Streams>().map(|s| s.clone());
+            if let Some(streams) = streams {
+                streams.wait().await;
+            }
+        })
+        .await
+    }
+
+    //ends the open streams and event sinks, used when the engine is about to be dropped
+    pub async fn close_streams(&self) {
+        async_with!(self.ctx => |ctx| {
+            let streams = ctx.userdata::<Streams>().map(|s| s.clone());
+            if let Some(streams) = streams {
+                streams.close();
+                streams.wait().await;
+            }
+        })
+        .await
+    }
+
+    //resource exhaustion gets its own AppError, everything else stays an anyhow error
+    fn map_js_error(&self, ctx: &Ctx, name: &str, err: rquickjs::Error) -> AppError {
+        match err {
+            rquickjs::Error::Allocation => AppError::JsOutOfMemory(name.to_string()),
+            rquickjs::Error::Exception => {
+                let exception = ctx.catch();
+                //quickjs throws null when it can't even allocate the "out of memory" error
+                if exception.is_null() && self.memory_limited {
+                    return AppError::JsOutOfMemory(name.to_string());
+                }
+                let exception = js_exception(name, exception);
+                match (exception.name.as_str(), exception.message.as_str()) {
+                    OUT_OF_MEMORY => AppError::JsOutOfMemory(name.to_string()),
+                    STACK_OVERFLOW => AppError::JsStackOverflow(name.to_string()),
+                    _ => AppError::JsError(exception),
+                }
+            }
+            e => anyhow::Error::from(e).into(),
+        }
+    }
+}
+
+//the message, name and stack of a thrown error, other thrown values are turned into a message
+fn js_exception(handler: &str, value: Value) -> JsException {
+    let (name, message, stack) = match value.as_object() {
+        Some(object) if value.is_error() => (
+            object.get::<_, Option<String>>("name").ok().flatten(),
+            object.get::<_, Option<String>>("message").ok().flatten(),
+            object.get::<_, Option<String>>("stack").ok().flatten(),
+        ),
+        _ => (None, value.get::<Coerced<String>>().ok().map(|v| v.0), None),
+    };
+    JsException {
+        handler: handler.to_string(),
+        name: name.unwrap_or_default(),
+        message: message.unwrap_or_default(),
+        stack: stack.filter(|s| !s.is_empty()),
+    }
+}
+
+async fn call_handler<'js>(ctx: &Ctx<'js>, name: &str, req: Req) -> rquickjs::Result<Res> {
+    let global = ctx.globals();
+    let handlers = global.get::<_, Object>("handlers")?;
+    let function = handlers.get::<_, Function>(name)?;
+    let result: Promise = function.call((req_into_js(ctx, req)?,))?;
+    result.into_future().await
+}
+
+async fn call_scheduled_handler<'js>(
+    ctx: &Ctx<'js>,
+    name: &str,
+    req: Req,
+) -> rquickjs::Result<Res> {
+    let global = ctx.globals();
+    let handlers = global.get::<_, Object>("handlers")?;
+    let function = handlers.get::<_, Function>(name)?;
+    let result: Value = function.call((req_into_js(ctx, req)?,))?;
+    let result = match result.as_promise() {
+        Some(promise) => promise.clone().into_future().await?,
+        None => result,
+    };
+    if result.is_undefined() || result.is_null() {
+        return Ok(Res {
+            headers: HashMap::new(),
+            body: None,
+            status: 204,
+        });
+    }
+    Res::from_js(ctx, result)
+}
+
+async fn call_hook<'js>(ctx: &Ctx<'js>, name: &str) -> rquickjs::Result<bool> {
+    let global = ctx.globals();
+    let handlers = global.get::<_, Object>("handlers")?;
+    let Some(function) = handlers.get::<_, Option<Function>>(name)? else {
+        return Ok(false);
+    };
+    let ret: Value = function.call(())?;
+    if let Some(promise) = ret.as_promise() {
+        promise.clone().into_future::<Value>().await?;
+    }
+    Ok(true)
+}
+
+//a rejected handler closes the sink, the client has already got the response head
+fn call_sse_handler<'js>(ctx: &Ctx<'js>, name: &str, req: Req) -> rquickjs::Result<Res> {
+    let global = ctx.globals();
+    let handlers = global.get::<_, Object>("handlers")?;
+    let function = handlers.get::<_, Function>(name)?;
+    let (sink, rx) = event_sink(ctx)?;
+    let ret: Value = function.call((req_into_js(ctx, req)?, sink.clone()))?;
+    if let Some(promise) = ret.into_promise() {
+        let inner = ctx.clone();
+        let name = name.to_string();
+        ctx.spawn(async move {
+            if let Err(e) = promise.into_future::<Value>().await {
+                warn!(
+                    "Sse handler {} failed: {}",
+                    name,
+                    js_error_message(&inner, e)
+                );
+                if let Ok(close) = sink.get::<_, Function>("close") {
+                    let _ = close.call::<_, Value>((This(sink),));
+                }
+            }
+        });
+    }
+    Ok(Res {
+        headers: HashMap::new(),
+        body: Some(ResBody::Events(rx)),
+        status: 200,
+    })
+}
+
+async fn call_ws_handler<'js>(ctx: &Ctx<'js>, name: &str, req: Req) -> rquickjs::Result<Res> {
+    let global = ctx.globals();
+    let handlers = global.get::<_, Object>("handlers")?;
+    let handler = handlers.get::<_, Object>(name)?;
+    let channel = open_socket(ctx, handler, req_into_js(ctx, req)?).await?;
+    Ok(Res {
+        headers: HashMap::new(),
+        body: Some(ResBody::Socket(channel)),
+        status: 101,
+    })
+}
+
+fn req_into_js<'js>(ctx: &Ctx<'js>, req: Req) -> rquickjs::Result<Object<'js>> {
+    let text = req
+        .body
+        .as_ref()
+        .map(|body| String::from_utf8_lossy(&body.0).into_owned())
+        .unwrap_or_default();
+    let obj = Object::from_value(req.into_js(ctx)?)?;
+    obj.set(
+        "text",
+        Function::new(ctx.clone(), move || text.clone())?.with_name("text")?,
+    )?;
+    //`req.timing("db", 12.5, "users")` adds `db;dur=12.5;desc="users"` to the Server-Timing header
+    let timing = ServerTiming::current();
+    let add_timing = move |name: String, dur: f64, desc: Opt<String>| {
+        if !timing::is_valid_name(&name) || !dur.is_finite() || dur < 0.0 {
+            return Err(rquickjs::Error::new_from_js_message(
+                "value",
+                "timing",
+                "expected a token as name and a positive duration in milliseconds",
+            ));
+        }
+        if let Some(timing) = &timing {
+            timing.record_ms(name, dur, desc.0);
+        }
+        Ok(())
+    };
+    obj.set(
+        "timing",
+        Function::new(ctx.clone(), add_timing)?.with_name("timing")?,
+    )?;
+    Ok(obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = r#"
+        (function(){
+            async function echo(req){
+                return {
+                    status: 200,
+                    headers: {},
+                    body: req.body,
+                };
+            }
+            async function text(req){
+                return {
+                    status: 200,
+                    headers: {},
+                    body: req.text() + " " + (req.body instanceof Uint8Array),
+                };
+            }
+            async function buffer(req){
+                return {
+                    status: 200,
+                    headers: {},
+                    body: new Uint8Array([0, 159, 146, 150]).buffer,
+                };
+            }
+            async function invalid(req){
+                return { status: 200, headers: {}, body: 42 };
+            }
+            let flushed = false;
+            async function onShutdown(){
+                await new Promise((resolve) => setTimeout(resolve, 5));
+                flushed = true;
+            }
+            async function flushed_(req){
+                return { status: 200, headers: {}, body: String(flushed) };
+            }
+            async function typeError(req){
+                return req.missing.field;
+            }
+            async function throwString(req){
+                throw "nope";
+            }
+            async function fakeOutOfMemory(req){
+                throw new Error("out of memory");
+            }
+            async function fakeStackOverflow(req){
+                throw new Error("Maximum call stack size exceeded");
+            }
+            return {echo, text, buffer, invalid, onShutdown, flushed: flushed_, typeError, throwString,
+                fakeOutOfMemory, fakeStackOverflow};
+        })();
+    "#;
+
+    fn req(body: impl Into<JsBody>) -> Req {
+        Req::builder()
+            .method("POST")
+            .url("/")
+            .body(Some(body.into()))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn binary_body_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        let bytes: &'static [u8] = &[0xff, 0x00, 0xfe, 0x01];
+        let res = engine.run("echo", req(bytes), timeout).await.unwrap();
+        assert_eq!(res.body, Some(bytes.into()));
+
+        let res = engine.run("text", req("hello"), timeout).await.unwrap();
+        assert_eq!(res.body, Some("hello true".into()));
+
+        let res = engine.run("buffer", req(""), timeout).await.unwrap();
+        assert_eq!(res.body, Some(vec![0u8, 159, 146, 150].into()));
+
+        assert!(engine.run("invalid", req(""), timeout).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn run_hook_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        assert!(engine.run_hook("onShutdown", timeout).await.unwrap());
+        let res = engine.run("flushed", req(""), timeout).await.unwrap();
+        assert_eq!(res.body, Some("true".into()));
+        assert!(!engine.run_hook("onMissing", timeout).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn js_exception_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        let Err(AppError::JsError(e)) = engine.run("typeError", req(""), timeout).await else {
+            panic!("a js error is expected");
+        };
+        assert_eq!(e.handler, "typeError");
+        assert_eq!(e.name, "TypeError");
+        assert!(e.message.contains("field"));
+        assert!(e.stack.unwrap().contains("at typeError"));
+
+        let Err(AppError::JsError(e)) = engine.run("throwString", req(""), timeout).await else {
+            panic!("a js error is expected");
+        };
+        assert_eq!((e.name.as_str(), e.message.as_str()), ("", "nope"));
+        assert_eq!(e.stack, None);
+        assert_eq!(e.to_string(), "Handler throwString threw nope");
+
+        //only what quickjs throws itself is taken for running out of memory or stack
+        for name in ["fakeOutOfMemory", "fakeStackOverflow"] {
+            let ret = engine.run(name, req(""), timeout).await;
+            assert!(matches!(ret, Err(AppError::JsError(_))), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_body_should_be_sent_unchanged() {
+        let res = Res {
+            headers: HashMap::new(),
+            body: Some(vec![0u8, 159, 146, 150].into()),
+            status: 200,
+        };
+        let res = Response::from(res);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &[0u8, 159, 146, 150]);
+    }
+
+    #[test]
+    fn invalid_response_should_be_server_error() {
+        let bad_status = Res {
+            headers: HashMap::new(),
+            body: None,
+            status: 1000,
+        };
+        let bad_header = Res {
+            headers: HashMap::from([("bad header".to_string(), "1".to_string())]),
+            body: Some("hello".into()),
+            status: 200,
+        };
+        for res in [bad_status, bad_header] {
+            let res = Response::from(res);
+            assert_eq!(res.status(), 500);
+        }
     }
 }
@@ -0,0 +1,667 @@
+use std::{
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::FutureExt as _;
+use tokio::sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, info_span, warn, Instrument as _, Span};
+
+use crate::{
+    jsengine::HandlerKind, metrics::metrics, AppError, JsEngine, PoolConfig, Req, Res,
+    RuntimeLimits, ServerTiming, SourceMap, DEFAULT_QUEUE_SIZE, DEFAULT_WAIT_TIMEOUT,
+};
+
+//export of the module called when the server shuts down
+const SHUTDOWN_HOOK: &str = "onShutdown";
+
+//extra room on the worker thread stack on top of the quickjs max stack size
+const THREAD_STACK_HEADROOM: usize = 1 << 20;
+
+//a job dispatched to one of the pool threads, the result is sent back through the oneshot channel
+struct Job {
+    handler: String,
+    kind: HandlerKind,
+    req: Req,
+    timeout: Duration,
+    tx: oneshot::Sender<Result<Res, AppError>>,
+    //span of the request, so what the worker logs for it carries the request id
+    span: Span,
+    //entries of the Server-Timing header of the request, if it was sent by one
+    timing: Option<ServerTiming>,
+    //when the job started waiting for an engine
+    queued: Instant,
+    //the queue slot is released once the worker is done with the job
+    _permit: OwnedSemaphorePermit,
+}
+
+//asks a worker to call the module's shutdown hook and exit
+struct Shutdown {
+    timeout: Duration,
+    done: oneshot::Sender<()>,
+}
+
+//jobs are taken by whichever worker gets the lock first, even one still driving open streams
+type Jobs = Arc<Mutex<mpsc::UnboundedReceiver<Job>>>;
+
+//a pool of pre-initialized JsEngine, each engine lives on its own dedicated thread
+//dropping the pool closes the channel, so all the threads exit once they finish their current job
+pub struct JsPool {
+    sender: mpsc::UnboundedSender<Job>,
+    size: usize,
+    //bounds the jobs running or waiting in the channel, so a slow tenant can't queue without limit
+    slots: Arc<Semaphore>,
+    wait_timeout: Duration,
+    //signaled or dropped with the pool, tells the workers to close the streams still open
+    closing: watch::Sender<()>,
+    //one per worker, a job could be picked up by any of them
+    controls: Vec<mpsc::Sender<Shutdown>>,
+    //the js stacks of the errors are remapped with it to the files the bundle was built from
+    source_map: Option<Arc<SourceMap>>,
+}
+
+impl JsPool {
+    //fails if not a single worker thread could be started, the ones failing are left out
+    pub fn new(code: &str, config: &PoolConfig, limits: RuntimeLimits) -> Result<Self> {
+        let workers = config.workers.unwrap_or_else(default_pool_size).max(1);
+        let queue_size = config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+        let wait_timeout = config.wait_timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let jobs: Jobs = Arc::new(Mutex::new(receiver));
+        let (closing, closed) = watch::channel(());
+        let mut controls = Vec::with_capacity(workers);
+        for i in 0..workers {
+            let code = code.to_string();
+            let jobs = jobs.clone();
+            let closed = closed.clone();
+            let (control_tx, control) = mpsc::channel(1);
+            let limits = limits.clone();
+            let mut builder = thread::Builder::new().name(format!("dino-js-{i}"));
+            //quickjs only checks the stack size it is told, the thread must really have that much
+            if let Some(stack_size) = limits.stack_size {
+                builder = builder.stack_size(stack_size + THREAD_STACK_HEADROOM);
+            }
+            match builder.spawn(move || worker(code, limits, jobs, control, closed)) {
+                Ok(_) => controls.push(control_tx),
+                Err(e) => warn!("Fail to spawn js worker thread {}: {}", i, e),
+            }
+        }
+        if controls.is_empty() {
+            return Err(anyhow!(
+                "Fail to spawn any of the {} js worker threads",
+                workers
+            ));
+        }
+        let size = controls.len();
+        Ok(Self {
+            sender,
+            size,
+            slots: Arc::new(Semaphore::new(size + queue_size)),
+            wait_timeout,
+            closing,
+            controls,
+            source_map: None,
+        })
+    }
+
+    pub fn with_source_map(mut self, source_map: Option<Arc<SourceMap>>) -> Self {
+        self.source_map = source_map;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub async fn run(&self, handler: &str, req: Req, timeout: Duration) -> Result<Res, AppError> {
+        self.dispatch(handler, HandlerKind::Http, req, timeout)
+            .await
+    }
+
+    //the events keep flowing after this returns, the worker stays busy until the sink is closed
+    pub async fn run_sse(
+        &self,
+        handler: &str,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        self.dispatch(handler, HandlerKind::Sse, req, timeout).await
+    }
+
+    //the socket stays on the worker that opened it until it is closed
+    pub async fn run_ws(
+        &self,
+        handler: &str,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        self.dispatch(handler, HandlerKind::Ws, req, timeout).await
+    }
+
+    pub async fn run_schedule(
+        &self,
+        handler: &str,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        self.dispatch(handler, HandlerKind::Schedule, req, timeout)
+            .await
+    }
+
+    async fn dispatch(
+        &self,
+        handler: &str,
+        kind: HandlerKind,
+        req: Req,
+        timeout: Duration,
+    ) -> Result<Res, AppError> {
+        let queued = Instant::now();
+        let permit = tokio::time::timeout(self.wait_timeout, self.slots.clone().acquire_owned())
+            .instrument(info_span!("engine_acquire"))
+            .await
+            .map_err(|_| AppError::QueueFull(self.retry_after()))?
+            .map_err(|_| anyhow!("Js engine queue is closed"))?;
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            handler: handler.to_string(),
+            kind,
+            req,
+            timeout,
+            tx,
+            span: Span::current(),
+            timing: ServerTiming::current(),
+            queued,
+            _permit: permit,
+        };
+        self.sender
+            .send(job)
+            .map_err(|_| anyhow!("No js engine available"))?;
+        let ret = rx
+            .await
+            .map_err(|_| anyhow!("Js engine exited before finishing handler {}", handler))?;
+        ret.map_err(|e| match (e, &self.source_map) {
+            (AppError::JsError(mut e), Some(source_map)) => {
+                e.stack = e.stack.map(|stack| source_map.remap_stack(&stack));
+                AppError::JsError(e)
+            }
+            (e, _) => e,
+        })
+    }
+
+    //ends the sse and websocket connections still open, the workers keep serving jobs
+    pub fn close_streams(&self) {
+        self.closing.send_replace(());
+    }
+
+    //gives each engine a chance to flush its state through the module's `onShutdown` export,
+    //the workers exit afterwards so the pool can't run anything else
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.close_streams();
+        let mut pending = Vec::with_capacity(self.controls.len());
+        for control in &self.controls {
+            let (done, rx) = oneshot::channel();
+            if control.try_send(Shutdown { timeout, done }).is_ok() {
+                pending.push(rx);
+            }
+        }
+        for rx in pending {
+            let _ = rx.await;
+        }
+    }
+
+    //a hint for the client on when the queue might have room again, at least one second
+    fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.wait_timeout.as_secs_f64().ceil().max(1.0) as u64)
+    }
+}
+
+//default pool size is the number of available cpus
+pub fn default_pool_size() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+//evaluates the code on a thread set up like a worker's and lists the handlers it doesn't export
+pub(crate) async fn missing_handlers(
+    code: &str,
+    limits: &RuntimeLimits,
+    handlers: Vec<(String, HandlerKind)>,
+) -> Result<Vec<String>> {
+    let (tx, rx) = oneshot::channel();
+    let (code, limits) = (code.to_string(), limits.clone());
+    let mut builder = thread::Builder::new().name("dino-js-check".to_string());
+    if let Some(stack_size) = limits.stack_size {
+        builder = builder.stack_size(stack_size + THREAD_STACK_HEADROOM);
+    }
+    builder.spawn(move || {
+        let ret = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(anyhow::Error::from)
+            .and_then(|rt| {
+                rt.block_on(async {
+                    let engine = JsEngine::new(&code, &limits).await?;
+                    Ok(engine.missing_handlers(&handlers).await)
+                })
+            });
+        let _ = tx.send(ret);
+    })?;
+    rx.await?
+}
+
+async fn create_engine(code: &str, limits: &RuntimeLimits) -> Result<JsEngine> {
+    let start = Instant::now();
+    let engine = JsEngine::new(code, limits)
+        .instrument(info_span!("engine_create"))
+        .await?;
+    metrics().observe_engine_creation(start.elapsed());
+    Ok(engine)
+}
+
+fn worker(
+    code: String,
+    limits: RuntimeLimits,
+    jobs: Jobs,
+    control: mpsc::Receiver<Shutdown>,
+    closed: watch::Receiver<()>,
+) {
+    //each worker drives its engine, the promises and the timers on its own single threaded runtime
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            warn!("Fail to create runtime for js engine: {}", e);
+            return;
+        }
+    };
+    rt.block_on(serve_jobs(code, limits, jobs, control, closed));
+    info!(
+        "Js worker {:?} exited",
+        thread::current().name().unwrap_or_default()
+    );
+}
+
+async fn serve_jobs(
+    code: String,
+    limits: RuntimeLimits,
+    jobs: Jobs,
+    mut control: mpsc::Receiver<Shutdown>,
+    mut closed: watch::Receiver<()>,
+) {
+    let mut engine = match create_engine(&code, &limits).await {
+        Ok(engine) => engine,
+        Err(e) => {
+            warn!("Fail to initialize js engine: {}", e);
+            return;
+        }
+    };
+    let (mut controlled, mut watching) = (true, true);
+    loop {
+        //the streams, event sinks and sockets opened by the previous jobs keep being driven while
+        //waiting for the next one, they don't hold a job slot
+        let job = tokio::select! {
+            biased;
+            shutdown = control.recv(), if controlled => match shutdown {
+                Some(Shutdown { timeout, done }) => {
+                    engine.close_streams().await;
+                    if let Err(e) = engine.run_hook(SHUTDOWN_HOOK, timeout).await {
+                        warn!("Shutdown hook failed: {}", e);
+                    }
+                    let _ = done.send(());
+                    break;
+                }
+                //the pool is gone, the jobs left in the channel are still served
+                None => {
+                    controlled = false;
+                    continue;
+                }
+            },
+            //the pool closes its streams e.g. when the tenant code was swapped
+            changed = closed.changed(), if watching => {
+                watching = changed.is_ok();
+                engine.close_streams().await;
+                continue;
+            }
+            job = next_job(&jobs) => match job {
+                Some(job) => job,
+                None => break,
+            },
+            _ = engine.drain_streams(), if engine.has_streams() => continue,
+        };
+        let out_of_memory = run_job(&engine, job).await;
+        //a runtime that ran out of memory may be left half broken, start over with a fresh one
+        if out_of_memory {
+            engine.close_streams().await;
+            match create_engine(&code, &limits).await {
+                Ok(new_engine) => engine = new_engine,
+                Err(e) => {
+                    warn!("Fail to re-initialize js engine: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+    engine.close_streams().await;
+}
+
+async fn next_job(jobs: &Jobs) -> Option<Job> {
+    jobs.lock().await.recv().await
+}
+
+//runs the handler of the job and sends its result back, true if the engine ran out of memory
+async fn run_job(engine: &JsEngine, job: Job) -> bool {
+    let Job {
+        handler,
+        kind,
+        req,
+        timeout,
+        tx,
+        span,
+        timing,
+        queued,
+        _permit,
+    } = job;
+    //the request was dropped while waiting in the queue, no need to run it
+    if tx.is_closed() {
+        return false;
+    }
+    if let Some(timing) = &timing {
+        timing.record("acquire", queued.elapsed(), None);
+    }
+    let start = Instant::now();
+    let run = info_span!(parent: &span, "js_run", handler = %handler, kind = ?kind);
+    let invoke = engine.invoke(kind, &handler, req, timeout).instrument(run);
+    //a panic in a handler should not take the worker thread down with it
+    let ret = AssertUnwindSafe(ServerTiming::scope(timing.clone(), invoke))
+        .catch_unwind()
+        .instrument(span.clone())
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Js handler {} panicked", handler).into()));
+    if let Some(timing) = &timing {
+        timing.record("handler", start.elapsed(), None);
+    }
+    let out_of_memory = matches!(ret, Err(AppError::JsOutOfMemory(_)));
+    if tx.send(ret).is_err() {
+        span.in_scope(|| {
+            warn!(
+                "Fail to send result of handler {}: receiver dropped",
+                handler
+            )
+        });
+    }
+    //what the handler left open is driven by the worker from now on, the slot is free
+    drop(_permit);
+    out_of_memory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResBody;
+    use axum::extract::ws::Message;
+
+    const CODE: &str = r#"
+        (function(){
+            async function hello(req){
+                return {
+                    status: 200,
+                    headers: {},
+                    body: "hello " + req.params.id,
+                };
+            }
+            async function forever(req){
+                while(true){}
+            }
+            async function hog(req){
+                let data = [];
+                while(true){ data.push(new Array(1024).fill(req.url)); }
+            }
+            async function recurse(req){
+                function f(n){ return f(n + 1) + 1; }
+                return f(0);
+            }
+            async function feed(req, events){
+                const id = setInterval(() => events.send("tick", "1"), 5);
+                events.onClose(() => clearInterval(id));
+            }
+            const echo = {
+                onMessage(socket, data) {
+                    socket.send(data);
+                },
+            };
+            return {hello:hello,forever:forever,hog:hog,recurse:recurse,feed:feed,echo:echo};
+        })();
+    "#;
+
+    fn pool_config(workers: usize) -> PoolConfig {
+        PoolConfig {
+            workers: Some(workers),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn js_pool_run_should_work() {
+        let pool = JsPool::new(CODE, &pool_config(2), Default::default()).unwrap();
+        for i in 0..4 {
+            let req = Req::builder()
+                .method("GET")
+                .url(format!("/api/hello/{i}"))
+                .params([("id".to_string(), i.to_string())].into())
+                .build();
+            let res = pool
+                .run("hello", req, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body, Some(format!("hello {i}").into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn js_pool_with_invalid_code_should_fail() {
+        let pool = JsPool::new("", &pool_config(1), Default::default()).unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool
+            .run("hello", req, Duration::from_secs(1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn js_pool_runaway_handler_should_timeout() {
+        let pool = JsPool::new(CODE, &pool_config(1), Default::default()).unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        let ret = pool.run("forever", req, Duration::from_millis(100)).await;
+        assert!(matches!(ret, Err(AppError::HandlerTimeout(name, _)) if name == "forever"));
+
+        //the engine should still be usable after being interrupted
+        let req = Req::builder().method("GET").url("/").build();
+        let res = pool
+            .run("hello", req, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn js_pool_resource_limits_should_work() {
+        let limits = RuntimeLimits {
+            memory: Some(8 << 20),
+            stack_size: Some(256 << 10),
+            gc_threshold: None,
+        };
+        let pool = JsPool::new(CODE, &pool_config(1), limits).unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        let ret = pool.run("hog", req, Duration::from_secs(5)).await;
+        assert!(matches!(ret, Err(AppError::JsOutOfMemory(_))), "{ret:?}");
+
+        let req = Req::builder().method("GET").url("/").build();
+        let ret = pool.run("recurse", req, Duration::from_secs(5)).await;
+        assert!(matches!(ret, Err(AppError::JsStackOverflow(_))), "{ret:?}");
+
+        let req = Req::builder().method("GET").url("/").build();
+        let res = pool
+            .run("hello", req, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+    }
+
+    #[test]
+    fn js_pool_without_workers_should_fail() {
+        //no thread can get a stack that large
+        let limits = RuntimeLimits {
+            stack_size: Some(1 << 50),
+            ..Default::default()
+        };
+        assert!(JsPool::new(CODE, &pool_config(2), limits).is_err());
+    }
+
+    #[tokio::test]
+    async fn js_pool_full_queue_should_reject() {
+        let config = PoolConfig {
+            workers: Some(1),
+            queue_size: Some(0),
+            wait_timeout: Some(Duration::from_millis(50)),
+        };
+        let pool = Arc::new(JsPool::new(CODE, &config, Default::default()).unwrap());
+        let busy = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let req = Req::builder().method("GET").url("/").build();
+                pool.run("forever", req, Duration::from_millis(500)).await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let req = Req::builder().method("GET").url("/").build();
+        let ret = pool.run("hello", req, Duration::from_secs(1)).await;
+        assert!(matches!(ret, Err(AppError::QueueFull(d)) if d == Duration::from_secs(1)));
+
+        assert!(matches!(
+            busy.await.unwrap(),
+            Err(AppError::HandlerTimeout(_, _))
+        ));
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool.run("hello", req, Duration::from_secs(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn js_pool_drop_should_close_event_sinks() {
+        let pool = JsPool::new(CODE, &pool_config(1), Default::default()).unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        let res = pool
+            .run_sse("feed", req, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let Some(ResBody::Events(mut rx)) = res.body else {
+            panic!("sse handler should answer with events");
+        };
+        assert!(rx.recv().await.is_some());
+
+        //same as a swap dropping the old pool
+        drop(pool);
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while rx.recv().await.is_some() {}
+        })
+        .await;
+        assert!(closed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn js_pool_open_event_sinks_should_not_hold_workers() {
+        let config = PoolConfig {
+            workers: Some(1),
+            queue_size: Some(0),
+            wait_timeout: Some(Duration::from_millis(50)),
+        };
+        let pool = JsPool::new(CODE, &config, Default::default()).unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        let res = pool
+            .run_sse("feed", req, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let Some(ResBody::Events(mut rx)) = res.body else {
+            panic!("sse handler should answer with events");
+        };
+
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool.run("hello", req, Duration::from_secs(1)).await.is_ok());
+        //the feed keeps ticking next to the jobs
+        for _ in 0..3 {
+            let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            assert!(event.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn js_pool_open_sockets_should_not_hold_workers() {
+        let config = PoolConfig {
+            workers: Some(1),
+            queue_size: Some(0),
+            wait_timeout: Some(Duration::from_millis(50)),
+        };
+        let pool = JsPool::new(CODE, &config, Default::default()).unwrap();
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            let req = Req::builder().method("GET").url("/ws").build();
+            let res = pool
+                .run_ws("echo", req, Duration::from_secs(1))
+                .await
+                .unwrap();
+            let Some(ResBody::Socket(channel)) = res.body else {
+                panic!("websocket handler should open a socket");
+            };
+            sockets.push(channel);
+        }
+
+        //the only worker and its slot are free again while the sockets stay open
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool.run("hello", req, Duration::from_secs(1)).await.is_ok());
+        for socket in &mut sockets {
+            socket
+                .incoming
+                .send(Message::Text("ping".into()))
+                .await
+                .unwrap();
+            let msg = tokio::time::timeout(Duration::from_secs(1), socket.outgoing.recv()).await;
+            assert_eq!(msg.unwrap(), Some(Message::Text("ping".into())));
+        }
+    }
+
+    #[tokio::test]
+    async fn js_pool_shutdown_should_run_hook() {
+        let code = r#"
+            (function(){
+                async function hello(req){
+                    return { status: 200, headers: {}, body: "hello" };
+                }
+                async function onShutdown(){
+                    while(true){}
+                }
+                return {hello, onShutdown};
+            })();
+        "#;
+        let pool = JsPool::new(code, &pool_config(2), Default::default()).unwrap();
+        //a runaway hook is interrupted, the shutdown still completes
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            pool.shutdown(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+
+        let req = Req::builder().method("GET").url("/").build();
+        assert!(pool
+            .run("hello", req, Duration::from_secs(1))
+            .await
+            .is_err());
+    }
+}
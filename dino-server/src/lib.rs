@@ -1,63 +1,226 @@
+mod admin;
+mod bindings;
 mod config;
 mod error;
 mod jsengine;
+mod jspool;
+mod metrics;
 mod middleware;
 mod router;
-use dashmap::DashMap;
+mod schedule;
+mod shutdown;
+mod source_map;
+mod sse;
+mod stream;
+mod telemetry;
+mod tenant;
+mod timing;
+mod tls;
+mod ws;
+pub use admin::AdminOptions;
 pub use error::*;
+use futures_util::{future::BoxFuture, FutureExt as _};
 pub use jsengine::*;
+pub use jspool::*;
 use matchit::Match;
-pub use middleware::ServiceTimeLayer;
-use std::collections::HashMap;
-use tracing::info;
+use metrics::{metrics, metrics_handler};
+use middleware::Tenant;
+pub use middleware::{
+    default_redacted_headers, AccessLog, AccessLogLayer, AccessLogOptions, AccessLogTarget,
+    ProblemLayer, RequestId, RequestIdLayer, RouteInfo, ServiceTimeLayer, DEFAULT_MAX_FILES,
+    DEFAULT_MAX_SIZE,
+};
+use std::{
+    collections::HashMap,
+    future::IntoFuture as _,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, info_span, warn, Span};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
-    body::Bytes,
-    extract::{Host, Query, State},
-    http::{request::Parts, Response},
-    response::IntoResponse,
-    routing::any,
+    body::{Body, Bytes, HttpBody},
+    extract::{ws::WebSocketUpgrade, FromRequestParts, Host, Query, State},
+    http::{header, request::Parts, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get},
     Router,
 };
 pub use config::*;
 use indexmap::IndexMap;
 pub use router::*;
+pub use schedule::{Scheduler, SCHEDULE_HEADER};
+pub use shutdown::{shutdown_signal, Drain, DEFAULT_DRAIN_TIMEOUT};
+pub use source_map::SourceMap;
+pub use stream::ResBody;
+pub use telemetry::{Telemetry, DEFAULT_OTLP_ENDPOINT};
+pub use tenant::Tenants;
+pub use timing::ServerTiming;
+pub use tls::{CertStore, TlsCert, TlsOptions, DEFAULT_RELOAD_INTERVAL};
 use tokio::net::TcpListener;
+use typed_builder::TypedBuilder;
 pub type ProjectRouters = IndexMap<String, Vec<ProjectRoute>>;
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AppState {
-    //key is host name
-    router: DashMap<String, SwappableAppRouter>,
+    tenants: Arc<Tenants>,
+    certs: Arc<CertStore>,
+    drain: Drain,
+    //normalized to a leading slash and no trailing one
+    mount_prefix: Option<Arc<str>>,
+    //prometheus metrics are also served on this path of the main listener
+    metrics_path: Option<Arc<str>>,
+    error_detail: ErrorDetail,
+}
+
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct ServerOptions {
+    //time given to in-flight requests and shutdown hooks once SIGTERM or SIGINT is received
+    #[builder(default = DEFAULT_DRAIN_TIMEOUT)]
+    pub drain_timeout: Duration,
+    //serves https with the certificates of the tenants instead of plain http
+    #[builder(default)]
+    pub tls: Option<TlsOptions>,
+    //listener for deploying and removing tenants at runtime
+    #[builder(default)]
+    pub admin: Option<AdminOptions>,
+    //host of the tenant answering the requests no other tenant claims
+    #[builder(default, setter(into))]
+    pub fallback: Option<String>,
+    //tenants are also served under `{prefix}/{tenant}/...`, the tenant looked up like an exact host,
+    //for ingresses sending every tenant on the same hostname
+    #[builder(default, setter(into))]
+    pub mount_prefix: Option<String>,
+    //one json line per request, nothing is logged without it
+    #[builder(default)]
+    pub access_log: Option<AccessLogOptions>,
+    //path serving the metrics without a token on the main port, e.g. `/metrics`, they are
+    //always served on `/metrics` of the admin api, the path hides any tenant route on it
+    #[builder(default, setter(into))]
+    pub metrics_path: Option<String>,
+    //what the problem+json of the errors tells, messages and js stacks are only sent in verbose
+    #[builder(default)]
+    pub error_detail: ErrorDetail,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
 }
 
 #[derive(Clone)]
 pub struct TenentRouter {
     pub host: String,
     pub router: SwappableAppRouter,
+    pub tls: Option<TlsCert>,
 }
 
-pub async fn start_server(port: u16, router: Vec<TenentRouter>) -> Result<()> {
+pub async fn start_server(
+    port: u16,
+    router: Vec<TenentRouter>,
+    options: ServerOptions,
+) -> Result<()> {
     // let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
     // tracing_subscriber::registry().with(layer).init();
 
-    let addr = format!("0.0.0.0:{}", port);
-    info!("Listening on {}", addr);
-    let listener = TcpListener::bind(addr).await?;
-    let map_router = DashMap::new();
-    for TenentRouter { host, router } in router {
-        map_router.insert(host, router);
+    let state = AppState::default();
+    for TenentRouter { host, router, tls } in router {
+        match (&options.tls, tls) {
+            (Some(_), Some(tls)) => state.certs.insert(host.clone(), tls)?,
+            (Some(_), None) => {
+                warn!(tenant = %host, "No certificate, the tenant can't be served over https")
+            }
+            (None, Some(_)) => {
+                warn!(tenant = %host, "Https is disabled, the certificate is ignored")
+            }
+            (None, None) => {}
+        }
+        state.tenants.register(&host, router)?;
     }
+    serve(port, state, options).await
+}
 
-    let app = Router::new()
-        .route("/*path", any(handler))
-        .layer(ServiceTimeLayer)
-        .with_state(AppState::new(map_router));
-    axum::serve(listener, app.into_make_service()).await?;
+//serves the tenants of the state, which can be registered and removed while running
+pub async fn serve(port: u16, mut state: AppState, options: ServerOptions) -> Result<()> {
+    state.tenants.set_fallback(options.fallback.as_deref());
+    state.mount_prefix = options
+        .mount_prefix
+        .as_deref()
+        .map(normalize_prefix)
+        .transpose()?;
+    state.metrics_path = options.metrics_path.as_deref().map(|p| p.into());
+    state.error_detail = options.error_detail;
+    let drain = state.drain.clone();
+    if let Some(admin) = &options.admin {
+        let addr = SocketAddr::new(admin.ip, admin.port);
+        let listener = TcpListener::bind(addr).await?;
+        info!("Admin api listening on {}", addr);
+        tokio::spawn(admin::serve(listener, state.clone(), admin.token.clone()));
+    }
+    let graceful = {
+        let state = state.clone();
+        async move {
+            shutdown_signal().await;
+            info!(
+                "Shutting down, draining connections for at most {:?}",
+                options.drain_timeout
+            );
+            state.start_draining();
+        }
+    };
+    let mut app = app(state.clone());
+    if let Some(access_log) = options.access_log {
+        app = app.layer(AccessLogLayer::new(AccessLog::new(access_log)?));
+    }
+    let server: BoxFuture<'static, std::io::Result<()>> = match options.tls {
+        Some(tls) => {
+            info!("Listening on 0.0.0.0:{} (https)", port);
+            tls::serve(port, app, state.certs.clone(), tls, drain.clone(), graceful).boxed()
+        }
+        None => {
+            let addr = format!("0.0.0.0:{}", port);
+            info!("Listening on {}", addr);
+            let listener = TcpListener::bind(addr).await?;
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(graceful)
+            .into_future()
+            .boxed()
+        }
+    };
+    let deadline = async {
+        let started = drain.started().await;
+        tokio::time::sleep_until((started + options.drain_timeout).into()).await;
+    };
+    tokio::select! {
+        ret = server => ret?,
+        _ = deadline => warn!("Drain deadline exceeded, dropping the connections left"),
+    }
 
+    let started = drain.started().await;
+    let remaining = (started + options.drain_timeout).saturating_duration_since(Instant::now());
+    state.shutdown(remaining).await;
+    info!("Server stopped");
     Ok(())
 }
 
+fn app(state: AppState) -> Router {
+    let mut router = Router::new();
+    if let Some(path) = &state.metrics_path {
+        router = router.route(path, get(metrics_handler));
+    }
+    router
+        .route("/*path", any(handler))
+        .layer(ProblemLayer::new(state.error_detail))
+        .layer(RequestIdLayer)
+        .layer(ServiceTimeLayer)
+        .with_state(state)
+}
+
 //only support json request and return json response
 async fn handler(
     State(state): State<AppState>,
@@ -65,35 +228,176 @@ async fn handler(
     Host(host): Host,
     Query(query): Query<HashMap<String, String>>,
     body: Option<Bytes>,
-) -> Result<impl IntoResponse, AppError> {
-    let router = get_router_by_host(host, state)?;
-    let path = parts.uri.path();
+) -> Response {
+    let start = Instant::now();
+    let (mut tenant, mut route) = (None, None);
+    let ret = handle(state, parts, host, query, body, &mut tenant, &mut route).await;
+    //only requests matched to a handler get series, unknown hosts and paths can't flood them
+    let mut res = match route {
+        Some(route) => {
+            if let Err(e) = &ret {
+                metrics().observe_error(&route, e);
+            }
+            let mut res = ret.into_response();
+            metrics().observe_request(&route, res.status().as_u16(), start.elapsed());
+            res.extensions_mut().insert(route);
+            res
+        }
+        None => ret.into_response(),
+    };
+    if let Some(tenant) = tenant {
+        res.extensions_mut().insert(Tenant(tenant));
+    }
+    res
+}
+
+//`tenant` is filled in once the host is found and `route` once the request is matched, errors
+//after that are logged against them
+async fn handle(
+    state: AppState,
+    parts: Parts,
+    host: String,
+    query: HashMap<String, String>,
+    body: Option<Bytes>,
+    tenant_out: &mut Option<String>,
+    route: &mut Option<RouteInfo>,
+) -> Result<Response, AppError> {
+    if state.drain.is_draining() {
+        return Err(AppError::ShuttingDown);
+    }
+    let start = Instant::now();
+    let timing = parts.extensions.get::<ServerTiming>().cloned();
+    let (router, mount) = info_span!("host_lookup", host = %host)
+        .in_scope(|| get_router(&state, host.clone(), parts.uri.path()))?;
+    let (mount, path) = parts.uri.path().split_at(mount);
+    let path = if path.is_empty() { "/" } else { path };
+    let tenant = match mount {
+        "" => tenant::strip_port(&host),
+        mount => mount.rsplit('/').next().unwrap_or_default(),
+    };
+    *tenant_out = Some(tenant.to_string());
     let method = parts.method.clone();
-    let matched = router.match_it(method, path)?;
+    let matched = info_span!("match_it", path).in_scope(|| router.match_it(method.clone(), path));
+    let matched = match matched {
+        Ok(matched) => matched,
+        //OPTIONS without a handler is answered with the methods allowed on the path
+        Err(AppError::RouterMethodNotAllow(Method::OPTIONS, allow)) => {
+            return Ok((StatusCode::NO_CONTENT, [(header::ALLOW, allow)]).into_response());
+        }
+        Err(e) => return Err(e),
+    };
     let handler = matched.value;
-    let req = get_request_parts(parts.clone(), body, matched, query)?;
-    let ret = JsEngine::new(&router.code)?.run(handler, req)?;
+    if let Some(timing) = &timing {
+        timing.record("route", start.elapsed(), None);
+    }
+    Span::current()
+        .record("tenant", tenant)
+        .record("handler", &handler.name)
+        .record("hash", &router.hash);
+    *route = Some(RouteInfo {
+        tenant: tenant.to_string(),
+        route: handler.route.clone(),
+        handler: handler.name.clone(),
+    });
+    //a websocket route only answers upgrade requests, checked before bothering the js
+    let ws = match handler.kind {
+        RouteKind::Ws => {
+            match WebSocketUpgrade::from_request_parts(&mut parts.clone(), &()).await {
+                Ok(ws) => Some(ws),
+                Err(rejection) => return Ok(rejection.into_response()),
+            }
+        }
+        _ => None,
+    };
+    let req = get_request_parts(parts.clone(), body, matched, query, mount, path)?;
+    let (pool, name, timeout) = (&router.pool, &handler.name, handler.timeout);
+    //the pool records how long the engine took to get and the handler to run
+    let run = async {
+        match handler.kind {
+            RouteKind::Http => pool.run(name, req, timeout).await,
+            RouteKind::Sse => pool.run_sse(name, req, timeout).await,
+            RouteKind::Ws => pool.run_ws(name, req, timeout).await,
+        }
+    };
+    let ret = ServerTiming::scope(timing.clone(), run).await;
+    let ret = ret.inspect_err(|e| match e {
+        AppError::HandlerTimeout(name, timeout) => {
+            warn!(tenant = %tenant, handler = %name, "Handler timed out after {:?}", timeout);
+        }
+        AppError::QueueFull(_) => {
+            warn!(tenant = %tenant, handler = %handler.name, "Js engine queue is full");
+        }
+        AppError::JsError(e) => {
+            let stack = e.stack.as_deref().unwrap_or_default();
+            warn!(tenant = %tenant, handler = %e.handler, stack, "{}", e);
+        }
+        _ => {}
+    })?;
 
-    Ok(Response::from(ret))
+    let start = Instant::now();
+    let res = match ws {
+        Some(ws) => ws::upgrade(ws, ret),
+        None if method == Method::HEAD => strip_body(Response::from(ret)),
+        None => Response::from(ret),
+    };
+    if let Some(timing) = &timing {
+        timing.record("response", start.elapsed(), None);
+    }
+    Ok(res)
 }
 
-fn get_router_by_host(host: String, state: AppState) -> Result<AppRouter, AppError> {
-    let host = host
-        .split(":")
-        .next()
-        .ok_or_else(|| AppError::HostNotFound("".to_string()))?;
+//a HEAD response keeps the headers of GET, including the length of the body it would have sent
+fn strip_body(res: Response) -> Response {
+    let (mut parts, body) = res.into_parts();
+    if let Some(len) = body.size_hint().exact() {
+        parts
+            .headers
+            .entry(header::CONTENT_LENGTH)
+            .or_insert_with(|| HeaderValue::from(len));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+//the tenant mounted under the path prefix, or else the one of the host, with the length of the
+//mount in front of the path
+fn get_router(state: &AppState, host: String, path: &str) -> Result<(AppRouter, usize), AppError> {
+    if let Some(prefix) = &state.mount_prefix {
+        if let Some(rest) = path
+            .strip_prefix(&**prefix)
+            .and_then(|p| p.strip_prefix('/'))
+        {
+            let tenant = rest.split('/').next().unwrap_or_default();
+            let router = state
+                .tenants
+                .get(tenant)
+                .ok_or_else(|| AppError::HostNotFound(tenant.to_string()))?;
+            return Ok((router.load(), prefix.len() + 1 + tenant.len()));
+        }
+    }
     let router = state
-        .router
-        .get(host)
-        .ok_or(AppError::HostNotFound(host.to_string()))?
+        .tenants
+        .resolve(&host)
+        .ok_or(AppError::HostNotFound(host))?
         .load();
-    Ok(router)
+    Ok((router, 0))
 }
+
+//a root prefix would mount the tenants where they already are, it is turned down
+fn normalize_prefix(prefix: &str) -> Result<Arc<str>> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return Err(anyhow!("Invalid mount prefix, it can't be empty or /"));
+    }
+    Ok(format!("/{}", prefix).into())
+}
+
 fn get_request_parts(
     parts: Parts,
     body: Option<Bytes>,
-    matched: Match<&str>,
+    matched: Match<&RouteHandler>,
     query: HashMap<String, String>,
+    mount: &str,
+    path: &str,
 ) -> Result<Req, AppError> {
     let headers = parts
         .headers
@@ -105,17 +409,23 @@ fn get_request_parts(
         .iter()
         .map(|(k, v)| (k.to_string(), v.to_string()))
         .collect();
-    let body = body.and_then(|b| {
-        let b = b.to_vec();
-        if b.is_empty() {
-            None
-        } else {
-            String::from_utf8(b).ok()
-        }
-    });
+    let body = body.filter(|b| !b.is_empty()).map(JsBody);
+    //a mounted tenant sees the url it would have been sent without the mount
+    let url = match (mount, parts.uri.query()) {
+        ("", _) => parts.uri.to_string(),
+        (_, Some(query)) => format!("{}?{}", path, query),
+        (_, None) => path.to_string(),
+    };
+    let id = parts
+        .extensions
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default();
     let req = Req::builder()
         .method(parts.method.to_string())
-        .url(parts.uri.to_string())
+        .url(url)
+        .mount(mount)
+        .id(id)
         .headers(headers)
         .body(body)
         .query(query)
@@ -125,13 +435,285 @@ fn get_request_parts(
 }
 
 impl AppState {
-    pub fn new(router: DashMap<String, SwappableAppRouter>) -> Self {
-        Self { router }
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    //in-flight requests finish on the code they started with, the pool goes away after them
+    pub fn remove(&self, host: &str) -> Option<SwappableAppRouter> {
+        self.certs.remove(host);
+        self.tenants.remove(host)
+    }
+
+    //certificates served over https, keyed by the same hosts as the routers
+    pub fn certs(&self) -> &CertStore {
+        &self.certs
+    }
+
+    //new requests are rejected from now on, open sse and websocket connections are closed and
+    //no scheduled run is started anymore
+    pub fn start_draining(&self) {
+        if !self.drain.start() {
+            return;
+        }
+        for entry in self.tenants.hosts.iter() {
+            let router = entry.value().load();
+            router.scheduler.stop();
+            router.pool.close_streams();
+        }
+    }
+
+    //runs the shutdown hook of every tenant, giving up on the ones still busy after the timeout
+    pub async fn shutdown(&self, timeout: Duration) {
+        //a tenant registered under several hosts has its hook run once
+        let mut pools: Vec<(String, Arc<JsPool>)> = Vec::new();
+        for entry in self.tenants.hosts.iter() {
+            let pool = entry.value().load().pool.clone();
+            if !pools.iter().any(|(_, p)| Arc::ptr_eq(p, &pool)) {
+                pools.push((entry.key().clone(), pool));
+            }
+        }
+        let hooks = pools.iter().map(|(host, pool)| async move {
+            if tokio::time::timeout(timeout, pool.shutdown(timeout))
+                .await
+                .is_err()
+            {
+                warn!(tenant = %host, "Shutdown hook didn't finish in {:?}", timeout);
+            }
+        });
+        futures_util::future::join_all(hooks).await;
     }
 }
 
 impl TenentRouter {
     pub fn new(host: String, router: SwappableAppRouter) -> Self {
-        Self { host, router }
+        Self {
+            host,
+            router,
+            tls: None,
+        }
+    }
+
+    //certificate served to the clients asking for this host over https
+    pub fn with_tls(mut self, tls: TlsCert) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use tower::ServiceExt as _;
+
+    #[tokio::test]
+    async fn strip_body_should_keep_content_length() {
+        let res = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("hello"))
+            .unwrap();
+        let res = strip_body(res);
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn draining_server_should_reject_requests() {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        let code = "(function(){ async function hello(req){ return { status: 200, headers: {}, body: 'hi' }; } return { hello }; })();";
+        let state = AppState::default();
+        let router = SwappableAppRouter::new(code.to_string(), config).unwrap();
+        state.tenants.register("localhost", router).unwrap();
+        let req = || {
+            Request::builder()
+                .uri("/api/hello/1")
+                .header(header::HOST, "localhost")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app(state.clone()).oneshot(req()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        state.start_draining();
+        let res = app(state.clone()).oneshot(req()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::CONNECTION], "close");
+        state.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn mounted_tenant_should_work() {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        let code = "(function(){ async function hello(req){ return { status: 200, headers: {}, body: [req.mount, req.url, req.params.id].join('|') }; } return { hello }; })();";
+        let mut state = AppState {
+            mount_prefix: Some(normalize_prefix("t/").unwrap()),
+            ..Default::default()
+        };
+        let router = SwappableAppRouter::new(code.to_string(), config).unwrap();
+        state.tenants.register("shop", router).unwrap();
+        let get = |uri: &str, host: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = |res: Response| async {
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let res = app(state.clone())
+            .oneshot(get("/t/shop/api/hello/1?x=1", "ingress.example"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "/t/shop|/api/hello/1?x=1|1");
+
+        //routing by host keeps working next to the mounts
+        let res = app(state.clone())
+            .oneshot(get("/api/hello/2", "shop"))
+            .await
+            .unwrap();
+        assert_eq!(body(res).await, "|/api/hello/2|2");
+
+        let res = app(state.clone())
+            .oneshot(get("/t/blog/api/hello/1", "ingress.example"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        //the tenant is known from the mount even if no route matches
+        let res = app(state.clone())
+            .oneshot(get("/t/shop/api/nope", "ingress.example"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let problem: serde_json::Value = serde_json::from_str(&body(res).await).unwrap();
+        assert_eq!(problem["code"], "path_not_found");
+        assert_eq!(problem["tenant"], "shop");
+
+        state.mount_prefix = None;
+        let res = app(state)
+            .oneshot(get("/t/shop/api/hello/1", "ingress.example"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn request_id_should_reach_js() {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        let code = "(function(){ async function hello(req){ return { status: 200, headers: {}, body: req.id }; } return { hello }; })();";
+        let state = AppState::default();
+        let router = SwappableAppRouter::new(code.to_string(), config).unwrap();
+        state.tenants.register("localhost", router).unwrap();
+        let req = Request::builder()
+            .uri("/api/hello/1")
+            .header(header::HOST, "localhost")
+            .header(middleware::REQUEST_ID_HEADER, "req-1")
+            .body(Body::empty())
+            .unwrap();
+        let res = app(state).oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[middleware::REQUEST_ID_HEADER], "req-1");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "req-1");
+    }
+
+    #[tokio::test]
+    async fn metrics_path_should_work() {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        let code = "(function(){ async function hello(req){ throw new Error('boom'); } return { hello }; })();";
+        let state = AppState {
+            metrics_path: Some("/metrics".into()),
+            ..Default::default()
+        };
+        let router = SwappableAppRouter::new(code.to_string(), config).unwrap();
+        state.tenants.register("metrics.localhost", router).unwrap();
+        let get = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::HOST, "metrics.localhost")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app(state.clone())
+            .oneshot(get("/api/hello/1"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let res = app(state).oneshot(get("/metrics")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("dino_js_errors_total{error=\"js_error\",handler=\"hello\",route=\"/api/hello/:id\",tenant=\"metrics.localhost\"} 1"));
+        assert!(text.contains("dino_tenant_swaps_total{tenant=\"metrics.localhost\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn server_timing_should_work() {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        let code = "(function(){ async function hello(req){ req.timing('db', 12.5, 'users'); return { status: 200, headers: {}, body: 'ok' }; } async function hello2(req){ req.timing('db time', 1); } return { hello, hello2 }; })();";
+        let state = AppState::default();
+        let router = SwappableAppRouter::new(code.to_string(), config).unwrap();
+        state.tenants.register("localhost", router).unwrap();
+        let get = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::HOST, "localhost")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app(state.clone())
+            .oneshot(get("/api/hello/1"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let timing = res.headers()["server-timing"].to_str().unwrap();
+        let names: Vec<_> = timing
+            .split(", ")
+            .map(|entry| entry.split(';').next().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["route", "acquire", "db", "handler", "response", "total"]
+        );
+        assert!(timing.contains("db;dur=12.500;desc=\"users\""));
+
+        //an invalid entry fails the handler
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/hello/1")
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = app(state).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn normalize_prefix_should_work() {
+        assert_eq!(&*normalize_prefix("t").unwrap(), "/t");
+        assert_eq!(&*normalize_prefix("/t/").unwrap(), "/t");
+        assert_eq!(&*normalize_prefix("/a/b/").unwrap(), "/a/b");
+        assert!(normalize_prefix("/").is_err());
+        assert!(normalize_prefix("").is_err());
     }
 }
@@ -0,0 +1,224 @@
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use prometheus::{
+    core::Collector, Encoder as _, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tracing::warn;
+
+use crate::{AppError, AppState, RouteInfo, SwappableAppRouter, Tenants};
+
+//js engines take from a few milliseconds to seconds for large bundles
+const ENGINE_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+//series recorded while serving, the tenant ones are read from the tenants on each scrape
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    errors: IntCounterVec,
+    engine_creation: Histogram,
+}
+
+//shared by every server of the process, like the js worker threads recording into it
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metrics should be valid"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let route = ["tenant", "route", "handler"];
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "dino_http_requests_total",
+                "Requests served by a js handler",
+            ),
+            &["tenant", "route", "handler", "status"],
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "dino_http_request_duration_seconds",
+                "Time taken to answer the requests served by a js handler",
+            ),
+            &route,
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("dino_js_errors_total", "Js handler failures by error"),
+            &["tenant", "route", "handler", "error"],
+        )?;
+        let engine_creation = Histogram::with_opts(
+            HistogramOpts::new(
+                "dino_engine_creation_seconds",
+                "Time taken to create a js engine and load the tenant code",
+            )
+            .buckets(ENGINE_BUCKETS.to_vec()),
+        )?;
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(engine_creation.clone()))?;
+        Ok(Self {
+            registry,
+            requests,
+            latency,
+            errors,
+            engine_creation,
+        })
+    }
+
+    pub fn observe_request(&self, route: &RouteInfo, status: u16, elapsed: Duration) {
+        let labels = [&*route.tenant, &*route.route, &*route.handler];
+        self.requests
+            .with_label_values(&[labels[0], labels[1], labels[2], &status.to_string()])
+            .inc();
+        self.latency
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_error(&self, route: &RouteInfo, error: &AppError) {
+        self.errors
+            .with_label_values(&[&route.tenant, &route.route, &route.handler, error.code()])
+            .inc();
+    }
+
+    pub fn observe_engine_creation(&self, elapsed: Duration) {
+        self.engine_creation.observe(elapsed.as_secs_f64());
+    }
+
+    //prometheus text format, with the build and swaps of every tenant registered at this time
+    pub fn render(&self, tenants: &Tenants) -> String {
+        let mut families = self.registry.gather();
+        match tenant_metrics(tenants) {
+            Ok(tenants) => families.extend(tenants),
+            Err(e) => warn!("Fail to collect tenant metrics: {}", e),
+        }
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&families, &mut buf) {
+            warn!("Fail to encode metrics: {}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+fn tenant_metrics(tenants: &Tenants) -> prometheus::Result<Vec<prometheus::proto::MetricFamily>> {
+    let build = IntGaugeVec::new(
+        Opts::new(
+            "dino_tenant_build_info",
+            "Build served by the tenant, the hash of its bundle",
+        ),
+        &["tenant", "hash"],
+    )?;
+    let swaps = IntCounterVec::new(
+        Opts::new(
+            "dino_tenant_swaps_total",
+            "Times the code of the tenant was swapped since it was registered",
+        ),
+        &["tenant"],
+    )?;
+    //a tenant registered under several hosts is reported once, under its primary host, or the
+    //first of its hosts if the primary one was dropped
+    let mut routers: Vec<(SwappableAppRouter, Vec<String>)> = Vec::new();
+    for entry in tenants.hosts.iter() {
+        let (host, router) = (entry.key(), entry.value());
+        match routers
+            .iter_mut()
+            .find(|(r, _)| Arc::ptr_eq(&r.inners, &router.inners))
+        {
+            Some((_, hosts)) => hosts.push(host.clone()),
+            None => routers.push((router.clone(), vec![host.clone()])),
+        }
+    }
+    for (router, hosts) in routers {
+        let host = router
+            .primary_host()
+            .filter(|primary| hosts.iter().any(|h| h == primary))
+            .or_else(|| hosts.iter().min().map(String::as_str))
+            .unwrap_or_default();
+        build.with_label_values(&[host, &router.load().hash]).set(1);
+        swaps.with_label_values(&[host]).inc_by(router.swaps());
+    }
+    let mut families = build.collect();
+    families.extend(swaps.collect());
+    Ok(families)
+}
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    metrics_response(&state)
+}
+
+pub(crate) fn metrics_response(state: &AppState) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        metrics().render(&state.tenants),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsException, ProjectConfig};
+
+    #[test]
+    fn metrics_render_should_work() {
+        let config = || -> ProjectConfig {
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap()
+        };
+        let code = "(function(){ return {}; })();".to_string();
+        let tenants = Tenants::default();
+        let router = SwappableAppRouter::new(code, config()).unwrap();
+        tenants.register("metrics.example", router.clone()).unwrap();
+        tenants
+            .register("*.metrics.example", router.clone())
+            .unwrap();
+        router
+            .swap("(function(){ return { a: 1 }; })();".to_string(), config())
+            .unwrap();
+
+        let route = RouteInfo {
+            tenant: "metrics.example".to_string(),
+            route: "/api/hello/:id".to_string(),
+            handler: "hello".to_string(),
+        };
+        let metrics = metrics();
+        metrics.observe_request(&route, 500, Duration::from_millis(3));
+        let error = AppError::JsError(JsException {
+            handler: "hello".to_string(),
+            name: "Error".to_string(),
+            message: "boom".to_string(),
+            stack: None,
+        });
+        metrics.observe_error(&route, &error);
+
+        let text = metrics.render(&tenants);
+        let hash = router.load().hash.clone();
+        assert!(text.contains(&format!(
+            "dino_tenant_build_info{{hash=\"{}\",tenant=\"metrics.example\"}} 1",
+            hash
+        )));
+        assert!(text.contains("dino_tenant_swaps_total{tenant=\"metrics.example\"} 1"));
+        //aliases don't repeat the series of their tenant
+        assert_eq!(text.matches("dino_tenant_swaps_total{").count(), 1);
+        assert_eq!(text.matches("dino_tenant_build_info{").count(), 1);
+        assert!(text.contains(
+            "dino_http_requests_total{handler=\"hello\",route=\"/api/hello/:id\",status=\"500\",tenant=\"metrics.example\"} 1"
+        ));
+        assert!(text.contains(
+            "dino_js_errors_total{error=\"js_error\",handler=\"hello\",route=\"/api/hello/:id\",tenant=\"metrics.example\"} 1"
+        ));
+        assert!(text.contains("dino_http_request_duration_seconds_bucket{handler=\"hello\""));
+        assert!(text.contains("dino_engine_creation_seconds_count"));
+    }
+}
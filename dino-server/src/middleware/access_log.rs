@@ -0,0 +1,489 @@
+use super::REQUEST_ID_HEADER;
+use anyhow::{Context as _, Result};
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap},
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+use typed_builder::TypedBuilder;
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+use tower::{Layer, Service};
+
+//rotate the file once it grows past this size
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+//rotated files kept next to the current one, older ones are deleted
+pub const DEFAULT_MAX_FILES: usize = 7;
+//lines waiting for the writer, lines beyond it are dropped rather than slowing requests down
+const BUFFER_SIZE: usize = 8192;
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct AccessLogOptions {
+    #[builder(default = AccessLogTarget::Stdout)]
+    pub target: AccessLogTarget,
+    //only used when writing to a file
+    #[builder(default = DEFAULT_MAX_SIZE)]
+    pub max_size: u64,
+    //rotate the file after this long even if it is below the max size
+    #[builder(default)]
+    pub max_age: Option<Duration>,
+    #[builder(default = DEFAULT_MAX_FILES)]
+    pub max_files: usize,
+    //request headers logged with their value replaced, compared case insensitively
+    #[builder(default = default_redacted_headers())]
+    pub redact_headers: Vec<String>,
+}
+
+impl Default for AccessLogOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+pub fn default_redacted_headers() -> Vec<String> {
+    [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+//what the handler matched, put in the response extensions for the access log
+#[derive(Debug, Clone, Default)]
+pub struct RouteInfo {
+    pub tenant: String,
+    pub route: String,
+    pub handler: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    time: String,
+    request_id: Option<String>,
+    host: Option<String>,
+    tenant: Option<String>,
+    route: Option<String>,
+    handler: Option<String>,
+    method: String,
+    path: String,
+    status: u16,
+    latency_us: u128,
+    //unknown for streamed bodies
+    bytes_in: Option<u64>,
+    bytes_out: Option<u64>,
+    client_ip: Option<String>,
+    headers: BTreeMap<String, String>,
+}
+
+//json lines written by a dedicated thread, so a slow disk never holds a request
+pub struct AccessLog {
+    tx: Sender<String>,
+    redact: HashSet<String>,
+}
+
+impl AccessLog {
+    //fails if the log file can't be opened
+    pub fn new(options: AccessLogOptions) -> Result<Self> {
+        let mut writer = match &options.target {
+            AccessLogTarget::Stdout => Writer::Stdout(io::stdout()),
+            AccessLogTarget::File(path) => Writer::File(RotatingFile::open(
+                path.clone(),
+                options.max_size,
+                options.max_age,
+                options.max_files,
+            )?),
+        };
+        let (tx, rx) = crossbeam_channel::bounded(BUFFER_SIZE);
+        thread::Builder::new()
+            .name("dino-access-log".to_string())
+            .spawn(move || writer.run(rx))?;
+        let redact = options
+            .redact_headers
+            .iter()
+            .map(|h| h.to_ascii_lowercase())
+            .collect();
+        Ok(Self { tx, redact })
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+        headers
+            .iter()
+            .map(|(k, v)| {
+                let value = match self.redact.contains(k.as_str()) {
+                    true => REDACTED.to_string(),
+                    false => String::from_utf8_lossy(v.as_bytes()).into_owned(),
+                };
+                (k.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => return warn!("Fail to serialize access log entry: {}", e),
+        };
+        match self.tx.try_send(line) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => warn!("Access log is lagging, entry dropped"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    log: Arc<AccessLog>,
+}
+
+impl AccessLogLayer {
+    pub fn new(log: AccessLog) -> Self {
+        Self { log: Arc::new(log) }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogMiddleware {
+            inner,
+            log: self.log.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogMiddleware<S> {
+    inner: S,
+    log: Arc<AccessLog>,
+}
+
+impl<S> Service<Request> for AccessLogMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let start = Instant::now();
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        let method = request.method().to_string();
+        let path = request.uri().path().to_string();
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| request.uri().host())
+            .map(String::from);
+        let bytes_in = request.body().size_hint().exact();
+        let client_ip = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        let headers = self.log.headers(request.headers());
+        let log = self.log.clone();
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            let route = response.extensions().get::<RouteInfo>().cloned();
+            let (tenant, route, handler) = match route {
+                Some(r) => (Some(r.tenant), Some(r.route), Some(r.handler)),
+                None => (None, None, None),
+            };
+            let entry = Entry {
+                time,
+                request_id: response
+                    .headers()
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from),
+                host,
+                tenant,
+                route,
+                handler,
+                method,
+                path,
+                status: response.status().as_u16(),
+                latency_us: start.elapsed().as_micros(),
+                bytes_in,
+                bytes_out: response.body().size_hint().exact(),
+                client_ip,
+                headers,
+            };
+            log.write(&entry);
+            Ok(response)
+        })
+    }
+}
+
+enum Writer {
+    Stdout(io::Stdout),
+    File(RotatingFile),
+}
+
+impl Writer {
+    //flushes whenever the queue is empty, so lines show up promptly without a write per line
+    fn run(&mut self, rx: Receiver<String>) {
+        while let Ok(line) = rx.recv() {
+            self.write_line(&line);
+            while let Ok(line) = rx.try_recv() {
+                self.write_line(&line);
+            }
+            let ret = match self {
+                Writer::Stdout(out) => out.flush(),
+                Writer::File(file) => file.flush(),
+            };
+            if let Err(e) = ret {
+                warn!("Fail to flush access log: {}", e);
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let ret = match self {
+            Writer::Stdout(out) => writeln!(out, "{}", line),
+            Writer::File(file) => file.write_line(line),
+        };
+        if let Err(e) = ret {
+            warn!("Fail to write access log: {}", e);
+        }
+    }
+}
+
+//the current file keeps its name, rotated ones get the time of the rotation appended
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    max_size: u64,
+    max_age: Option<Duration>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_age: Option<Duration>,
+        max_files: usize,
+    ) -> Result<Self> {
+        let (file, size) = Self::create(&path)
+            .with_context(|| format!("Fail to open access log {}", path.display()))?;
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size,
+            max_age,
+            max_files,
+        })
+    }
+
+    fn create(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((BufWriter::new(file), size))
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let expired = self.max_age.is_some_and(|age| self.opened.elapsed() >= age);
+        if self.size > 0 && (self.size + line.len() as u64 >= self.max_size || expired) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let suffix = Utc::now().format("%Y%m%dT%H%M%S%.6f");
+        fs::rename(&self.path, rotated_name(&self.path, &suffix.to_string()))?;
+        (self.file, self.size) = Self::create(&self.path)?;
+        self.opened = Instant::now();
+        self.prune()
+    }
+
+    //the rotation time sorts like the names, so the oldest files come first
+    fn prune(&self) -> io::Result<()> {
+        let prefix = rotated_name(&self.path, "");
+        let mut rotated: Vec<_> = fs::read_dir(prefix.parent().unwrap_or(Path::new(".")))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.to_string_lossy().starts_with(&*prefix.to_string_lossy()))
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for path in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn rotated_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt as _;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dino-access-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //the writer thread is asynchronous, wait for the lines to land
+    fn read_lines(path: &Path, count: usize) -> Vec<serde_json::Value> {
+        for _ in 0..100 {
+            let content = fs::read_to_string(path).unwrap_or_default();
+            let lines: Vec<_> = content.lines().map(String::from).collect();
+            if lines.len() >= count {
+                return lines
+                    .iter()
+                    .map(|l| serde_json::from_str(l).unwrap())
+                    .collect();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("access log should have {} lines", count);
+    }
+
+    #[tokio::test]
+    async fn access_log_should_work() {
+        let dir = temp_dir("entry");
+        let path = dir.join("access.log");
+        let options = AccessLogOptions::builder()
+            .target(AccessLogTarget::File(path.clone()))
+            .build();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    let mut res = Response::new(Body::from("hello"));
+                    res.extensions_mut().insert(RouteInfo {
+                        tenant: "shop".to_string(),
+                        route: "/".to_string(),
+                        handler: "hello".to_string(),
+                    });
+                    res
+                }),
+            )
+            .layer(AccessLogLayer::new(AccessLog::new(options).unwrap()));
+        let req = Request::get("/")
+            .header(header::HOST, "shop.example")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header("Cookie", "session=1")
+            .header(header::USER_AGENT, "curl")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let req = Request::post("/missing").body(Body::from("abc")).unwrap();
+        app.oneshot(req).await.unwrap();
+
+        let lines = read_lines(&path, 2);
+        let entry = &lines[0];
+        assert_eq!(entry["host"], "shop.example");
+        assert_eq!(entry["tenant"], "shop");
+        assert_eq!(entry["route"], "/");
+        assert_eq!(entry["handler"], "hello");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes_in"], 0);
+        assert_eq!(entry["bytes_out"], 5);
+        assert_eq!(entry["headers"]["authorization"], REDACTED);
+        assert_eq!(entry["headers"]["cookie"], REDACTED);
+        assert_eq!(entry["headers"]["user-agent"], "curl");
+
+        let entry = &lines[1];
+        assert_eq!(entry["status"], 404);
+        assert_eq!(entry["bytes_in"], 3);
+        assert!(entry["handler"].is_null());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_file_should_work() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 20, None, 2).unwrap();
+        for i in 0..5 {
+            file.write_line(&format!("line-{}-0123456789", i)).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line-4-0123456789\n");
+        //only the two latest rotated files are kept
+        let mut rotated: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| *p != path)
+            .collect();
+        rotated.sort();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(
+            fs::read_to_string(&rotated[1]).unwrap(),
+            "line-3-0123456789\n"
+        );
+
+        let mut file =
+            RotatingFile::open(path.clone(), DEFAULT_MAX_SIZE, Some(Duration::ZERO), 2).unwrap();
+        file.write_line("after").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
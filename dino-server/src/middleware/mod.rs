@@ -1,5 +1,15 @@
+mod access_log;
+mod problem;
+mod request_id;
 mod service_time;
 
 pub const REQUEST_ID_HEADER: &str = "x-request-id";
 
+pub use access_log::{
+    default_redacted_headers, AccessLog, AccessLogLayer, AccessLogOptions, AccessLogTarget,
+    RouteInfo, DEFAULT_MAX_FILES, DEFAULT_MAX_SIZE,
+};
+pub use problem::ProblemLayer;
+pub(crate) use problem::Tenant;
+pub use request_id::{RequestId, RequestIdLayer};
 pub use service_time::ServiceTimeLayer;
@@ -0,0 +1,187 @@
+use axum::{body::Body, extract::Request, http::header, response::Response};
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use super::RequestId;
+use crate::{ErrorDetail, Problem};
+
+//tenant the request was sent to, in the response extensions once its host is found
+#[derive(Debug, Clone)]
+pub(crate) struct Tenant(pub String);
+
+//renders the problem+json of the errors again with the request id, the tenant and the detail
+//configured, it goes inside the request id layer
+#[derive(Clone)]
+pub struct ProblemLayer {
+    detail: ErrorDetail,
+}
+
+impl ProblemLayer {
+    pub fn new(detail: ErrorDetail) -> Self {
+        Self { detail }
+    }
+}
+
+impl<S> Layer<S> for ProblemLayer {
+    type Service = ProblemMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ProblemMiddleware {
+            inner,
+            detail: self.detail,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProblemMiddleware<S> {
+    inner: S,
+    detail: ErrorDetail,
+}
+
+impl<S> Service<Request> for ProblemMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let id = request.extensions().get::<RequestId>().cloned();
+        let detail = self.detail;
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            if let Some(problem) = response.extensions_mut().remove::<Problem>() {
+                let tenant = response
+                    .extensions()
+                    .get::<Tenant>()
+                    .map(|tenant| tenant.0.as_str());
+                let id = id.as_ref().map(|id| id.0.as_str());
+                let body = problem.body(detail, tenant, id);
+                *response.body_mut() = Body::from(body);
+                response.headers_mut().remove(header::CONTENT_LENGTH);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware::REQUEST_ID_HEADER, AppError, JsException, RequestIdLayer};
+    use anyhow::anyhow;
+    use axum::{response::IntoResponse, routing::get, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt as _;
+
+    fn app(detail: ErrorDetail) -> Router {
+        Router::new()
+            .route(
+                "/host",
+                get(|| async { AppError::HostNotFound("a.com".to_string()).into_response() }),
+            )
+            .route(
+                "/internal",
+                get(|| async {
+                    let mut res = AppError::from(anyhow!("db is down").context("fail to load"))
+                        .into_response();
+                    res.extensions_mut()
+                        .insert(Tenant("app.example".to_string()));
+                    res
+                }),
+            )
+            .route(
+                "/js",
+                get(|| async {
+                    AppError::JsError(JsException {
+                        handler: "hello".to_string(),
+                        name: "TypeError".to_string(),
+                        message: "x is undefined".to_string(),
+                        stack: Some("    at hello (main.mjs:3:10)\n".to_string()),
+                    })
+                    .into_response()
+                }),
+            )
+            .layer(ProblemLayer::new(detail))
+            .layer(RequestIdLayer)
+    }
+
+    async fn problem(app: Router, uri: &str) -> (Response, Value) {
+        let req = Request::get(uri)
+            .header(REQUEST_ID_HEADER, "req-1")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let res = Response::from_parts(parts, Body::empty());
+        (res, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn problem_should_have_request_id() {
+        let (res, body) = problem(app(ErrorDetail::Prod), "/host").await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(
+            body,
+            json!({
+                "type": "urn:dino:error:host_not_found",
+                "title": "Host not found",
+                "status": 404,
+                "detail": "Host not found: a.com",
+                "code": "host_not_found",
+                "request_id": "req-1",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_detail_should_depend_on_mode() {
+        let (res, body) = problem(app(ErrorDetail::Prod), "/internal").await;
+        assert_eq!(res.status(), 500);
+        assert_eq!(
+            body,
+            json!({
+                "type": "urn:dino:error:internal",
+                "title": "Internal server error",
+                "status": 500,
+                "code": "internal",
+                "tenant": "app.example",
+                "request_id": "req-1",
+            })
+        );
+
+        let (_, body) = problem(app(ErrorDetail::Verbose), "/internal").await;
+        assert_eq!(body["detail"], "fail to load: db is down");
+        assert_eq!(body["tenant"], "app.example");
+
+        //js stacks are only sent in verbose mode
+        let (_, body) = problem(app(ErrorDetail::Prod), "/js").await;
+        assert_eq!(body["code"], "js_error");
+        assert!(body.get("detail").is_none() && body.get("stack").is_none());
+        let (_, body) = problem(app(ErrorDetail::Verbose), "/js").await;
+        assert_eq!(
+            body["detail"],
+            "Handler hello threw TypeError: x is undefined"
+        );
+        assert_eq!(body["stack"], "    at hello (main.mjs:3:10)\n");
+    }
+}
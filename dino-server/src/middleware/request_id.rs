@@ -0,0 +1,153 @@
+use super::REQUEST_ID_HEADER;
+use axum::{extract::Request, http::HeaderValue, response::Response};
+use tracing::{field, info_span, Instrument as _};
+use uuid::Uuid;
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::telemetry::set_remote_parent;
+
+//an id sent by the client is kept if it is at most this long
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+//id of the request, in the request extensions for the handlers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+//keeps the x-request-id of the client or generates an UUIDv7, echoes it on the response and
+//runs the request in a span carrying it
+#[derive(Clone)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestIdMiddleware<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for RequestIdMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| is_valid(v))
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| Uuid::now_v7().to_string());
+        //only visible ascii gets here, it is a valid header value either way
+        let value = HeaderValue::from_str(&id).expect("request id should be a valid header");
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, value.clone());
+        request.extensions_mut().insert(RequestId(id.clone()));
+        //the tenant, handler and build are recorded once the request is routed
+        let span = info_span!(
+            "request",
+            otel.kind = "server",
+            request_id = %id,
+            method = %request.method(),
+            path = %request.uri().path(),
+            tenant = field::Empty,
+            handler = field::Empty,
+            hash = field::Empty,
+        );
+        set_remote_parent(&span, request.headers());
+        let future = self.inner.call(request);
+        Box::pin(
+            async move {
+                let mut response = future.await?;
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use tower::ServiceExt as _;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|Extension(id): Extension<RequestId>| async move { id.0 }),
+            )
+            .layer(RequestIdLayer)
+    }
+
+    async fn body(res: Response) -> String {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_id_should_be_generated() {
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        let id = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let uuid = Uuid::parse_str(&id).unwrap();
+        assert_eq!(uuid.get_version_num(), 7);
+        assert_eq!(body(res).await, id);
+    }
+
+    #[tokio::test]
+    async fn request_id_should_be_kept_if_valid() {
+        let ulid = "01JF8ZQ6W3Y2S6X0J4X5T1B2C3";
+        let req = Request::get("/")
+            .header(REQUEST_ID_HEADER, ulid)
+            .body(Body::empty())
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], ulid);
+        assert_eq!(body(res).await, ulid);
+
+        let req = Request::get("/")
+            .header(REQUEST_ID_HEADER, "a b\"c")
+            .body(Body::empty())
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        assert_ne!(res.headers()[REQUEST_ID_HEADER], "a b\"c");
+    }
+}
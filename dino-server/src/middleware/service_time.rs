@@ -1,4 +1,5 @@
 use super::REQUEST_ID_HEADER;
+use crate::ServerTiming;
 use axum::{extract::Request, http::HeaderValue, response::Response};
 use tokio::time::Instant;
 use tracing::warn;
@@ -10,6 +11,7 @@ use std::{
 };
 use tower::{Layer, Service};
 const SERVICE_TIME_HEADER: &str = "x-service-time";
+const SERVER_TIMING_HEADER: &str = "server-timing";
 
 #[derive(Clone)]
 pub struct ServiceTimeLayer;
@@ -43,12 +45,22 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
+    fn call(&mut self, mut request: Request) -> Self::Future {
         let start = Instant::now();
+        let timing = ServerTiming::default();
+        request.extensions_mut().insert(timing.clone());
         let future = self.inner.call(request);
         Box::pin(async move {
             let mut response: Response = future.await?;
-            let elapsed = format!("{}us", start.elapsed().as_micros());
+            let total = start.elapsed();
+            //added after the entries the handler may have set itself
+            match HeaderValue::from_str(&timing.header_value(total)) {
+                Ok(v) => {
+                    response.headers_mut().append(SERVER_TIMING_HEADER, v);
+                }
+                Err(e) => warn!("Invalid server timing: {}", e),
+            }
+            let elapsed = format!("{}us", total.as_micros());
             match elapsed.parse::<HeaderValue>() {
                 Ok(v) => {
                     response.headers_mut().insert(SERVICE_TIME_HEADER, v);
@@ -1,40 +1,192 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context as _, Result};
 use arc_swap::ArcSwap;
 use axum::http::Method;
 use matchit::{Match, Router};
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
 
-use crate::{AppError, ProjectRouters};
+use crate::{
+    jsengine::HandlerKind,
+    jspool::missing_handlers,
+    schedule::{Running, Scheduler},
+    AppError, JsPool, ProjectConfig, RouteKind, SourceMap, DEFAULT_HANDLER_TIMEOUT,
+};
+
+//the code of a tenant, with the source map written next to it by `dino build` if there is one
+#[derive(Debug, Clone, Default)]
+pub struct JsBundle {
+    pub code: String,
+    pub source_map: Option<String>,
+}
+
+impl From<String> for JsBundle {
+    fn from(code: String) -> Self {
+        Self {
+            code,
+            source_map: None,
+        }
+    }
+}
+
+impl JsBundle {
+    //evaluates the code once and makes sure every handler the config refers to is exported,
+    //so a broken bundle is turned down before it replaces the one being served
+    pub async fn check(&self, config: &ProjectConfig) -> Result<()> {
+        let mut handlers: Vec<(String, HandlerKind)> = config
+            .routes
+            .values()
+            .flatten()
+            .map(|route| (route.handler.clone(), route.kind.into()))
+            .collect();
+        handlers.extend(
+            config
+                .schedules
+                .iter()
+                .map(|schedule| (schedule.handler.clone(), HandlerKind::Schedule)),
+        );
+        let mut missing = missing_handlers(&self.code, &config.limits, handlers)
+            .await
+            .context("Fail to evaluate the bundle")?;
+        missing.sort();
+        missing.dedup();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Handlers not exported by the bundle: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Clone)]
 pub struct SwappableAppRouter {
     pub inners: Arc<ArcSwap<AppRouterInner>>,
+    running: Running,
+    //successful swaps since the router was created
+    swaps: Arc<AtomicU64>,
+    //first host the router was registered under, the tenant it is reported as
+    primary: Arc<OnceLock<String>>,
 }
 pub struct AppRouterInner {
     pub code: String,
+    //identifies the bundle being served, first 12 hex digits of its blake3 hash
+    pub hash: String,
     pub router: Router<MethodRoute>,
+    pub pool: Arc<JsPool>,
+    //the schedules stop with the inner, so a swap reloads them with the new code
+    pub scheduler: Scheduler,
 }
 
 #[derive(Clone)]
 pub struct AppRouter(Arc<AppRouterInner>);
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteHandler {
+    pub name: String,
+    //the path pattern of the route, as written in config.yml
+    pub route: String,
+    //the effective timeout, route level timeout first then project level
+    pub timeout: Duration,
+    pub kind: RouteKind,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct MethodRoute {
-    get: Option<String>,
-    head: Option<String>,
-    delete: Option<String>,
-    options: Option<String>,
-    patch: Option<String>,
-    post: Option<String>,
-    put: Option<String>,
-    trace: Option<String>,
-    connect: Option<String>,
+    get: Option<RouteHandler>,
+    head: Option<RouteHandler>,
+    delete: Option<RouteHandler>,
+    options: Option<RouteHandler>,
+    patch: Option<RouteHandler>,
+    post: Option<RouteHandler>,
+    put: Option<RouteHandler>,
+    trace: Option<RouteHandler>,
+    connect: Option<RouteHandler>,
+}
+impl MethodRoute {
+    //the order in which methods are listed in the Allow header
+    const METHODS: [Method; 9] = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::CONNECT,
+    ];
+
+    fn slot_mut(&mut self, method: &Method) -> Option<&mut Option<RouteHandler>> {
+        match *method {
+            Method::GET => Some(&mut self.get),
+            Method::HEAD => Some(&mut self.head),
+            Method::DELETE => Some(&mut self.delete),
+            Method::OPTIONS => Some(&mut self.options),
+            Method::PATCH => Some(&mut self.patch),
+            Method::POST => Some(&mut self.post),
+            Method::PUT => Some(&mut self.put),
+            Method::TRACE => Some(&mut self.trace),
+            Method::CONNECT => Some(&mut self.connect),
+            _ => None,
+        }
+    }
+
+    fn slot(&self, method: &Method) -> Option<&RouteHandler> {
+        match *method {
+            Method::GET => self.get.as_ref(),
+            //HEAD falls back to GET, the body is stripped when sending the response
+            Method::HEAD => self.head.as_ref().or(self.get.as_ref()),
+            Method::DELETE => self.delete.as_ref(),
+            Method::OPTIONS => self.options.as_ref(),
+            Method::PATCH => self.patch.as_ref(),
+            Method::POST => self.post.as_ref(),
+            Method::PUT => self.put.as_ref(),
+            Method::TRACE => self.trace.as_ref(),
+            Method::CONNECT => self.connect.as_ref(),
+            _ => None,
+        }
+    }
+
+    //value of the Allow header, OPTIONS is always allowed since it is answered automatically
+    pub fn allow(&self) -> String {
+        Self::METHODS
+            .iter()
+            .filter(|m| **m == Method::OPTIONS || self.slot(m).is_some())
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
+
 impl AppRouterInner {
-    pub fn new(code: String, router: Router<MethodRoute>) -> Self {
-        Self { code, router }
+    pub fn new(
+        code: String,
+        router: Router<MethodRoute>,
+        pool: Arc<JsPool>,
+        scheduler: Scheduler,
+    ) -> Self {
+        Self {
+            hash: build_hash(&code),
+            code,
+            router,
+            pool,
+            scheduler,
+        }
     }
 }
+pub fn build_hash(code: &str) -> String {
+    let mut hash = blake3::hash(code.as_bytes()).to_string();
+    hash.truncate(12);
+    hash
+}
+
 impl Deref for AppRouter {
     type Target = AppRouterInner;
     fn deref(&self) -> &Self::Target {
@@ -42,81 +194,110 @@ impl Deref for AppRouter {
     }
 }
 impl SwappableAppRouter {
-    pub fn new(code: String, routes: ProjectRouters) -> Result<Self> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    pub fn new(bundle: impl Into<JsBundle>, config: ProjectConfig) -> Result<Self> {
+        let running = Running::default();
+        let inner = Self::build(bundle.into(), config, running.clone())?;
         Ok(Self {
             inners: Arc::new(ArcSwap::from_pointee(inner)),
+            running,
+            swaps: Default::default(),
+            primary: Default::default(),
         })
     }
-    pub fn swap(&self, code: String, routes: ProjectRouters) -> Result<()> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    pub fn swap(&self, bundle: impl Into<JsBundle>, config: ProjectConfig) -> Result<()> {
+        let inner = Self::build(bundle.into(), config, self.running.clone())?;
         self.inners.store(Arc::new(inner));
+        self.swaps.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
+    pub fn swaps(&self) -> u64 {
+        self.swaps.load(Ordering::Relaxed)
+    }
+
+    pub fn primary_host(&self) -> Option<&str> {
+        self.primary.get().map(String::as_str)
+    }
+
+    pub(crate) fn set_primary_host(&self, host: &str) {
+        self.primary.get_or_init(|| host.to_string());
+    }
+
+    //engines are warmed up with the new code, the old pool is dropped with the old inner
+    fn build(
+        bundle: JsBundle,
+        mut config: ProjectConfig,
+        running: Running,
+    ) -> Result<AppRouterInner> {
+        let JsBundle { code, source_map } = bundle;
+        let source_map = source_map
+            .as_deref()
+            .map(SourceMap::parse)
+            .transpose()
+            .context("Invalid source map")?;
+        let schedules = std::mem::take(&mut config.schedules);
+        let default_timeout = config.timeout.unwrap_or(DEFAULT_HANDLER_TIMEOUT);
+        let name = config.name.clone();
+        let pool = JsPool::new(&code, &config.pool, config.limits.clone())?
+            .with_source_map(source_map.map(Arc::new));
+        let pool = Arc::new(pool);
+        let router = Self::get_router(config)?;
+        let scheduler = Scheduler::new(&name, schedules, default_timeout, pool.clone(), running)?;
+        Ok(AppRouterInner::new(code, router, pool, scheduler))
+    }
+
     pub fn load(&self) -> AppRouter {
         AppRouter(self.inners.load_full())
     }
 
-    fn get_router(routes: ProjectRouters) -> Result<Router<MethodRoute>> {
+    fn get_router(config: ProjectConfig) -> Result<Router<MethodRoute>> {
+        let default_timeout = config.timeout.unwrap_or(DEFAULT_HANDLER_TIMEOUT);
         let mut router = Router::new();
-        for (path, methods) in routes {
+        for (path, methods) in config.routes {
             let mut method_route = MethodRoute::default();
             for method in methods {
-                match method.method {
-                    Method::GET => method_route.get = Some(method.handler),
-                    Method::HEAD => method_route.head = Some(method.handler),
-                    Method::DELETE => method_route.delete = Some(method.handler),
-                    Method::OPTIONS => method_route.options = Some(method.handler),
-                    Method::PATCH => method_route.patch = Some(method.handler),
-                    Method::POST => method_route.post = Some(method.handler),
-                    Method::PUT => method_route.put = Some(method.handler),
-                    Method::TRACE => method_route.trace = Some(method.handler),
-                    Method::CONNECT => method_route.connect = Some(method.handler),
-                    _ => {
-                        panic!("Unsupported method");
-                    }
+                let handler = RouteHandler {
+                    name: method.handler,
+                    route: path.clone(),
+                    timeout: method.timeout.unwrap_or(default_timeout),
+                    kind: method.kind,
+                };
+                let slot = method_route
+                    .slot_mut(&method.method)
+                    .ok_or_else(|| anyhow!("Unsupported method {} for {}", method.method, path))?;
+                if let Some(old) = slot.replace(handler) {
+                    return Err(anyhow!(
+                        "Duplicate {} handler for {}: {}",
+                        method.method,
+                        path,
+                        old.name
+                    ));
                 }
             }
-            // println!("path: {path}, method_route: {method_route:?}");
-            // let str=method_route.
-            router.insert(path, method_route)?;
+            router
+                .insert(path.clone(), method_route)
+                .map_err(|e| anyhow!("Invalid route {}: {}", path, e))?;
         }
         Ok(router)
     }
 }
-#[allow(elided_named_lifetimes)]
 impl AppRouter {
     pub fn match_it<'m, 'p>(
         &'m self,
         method: Method,
         path: &'p str,
-    ) -> Result<Match<&'m str>, AppError>
+    ) -> Result<Match<'m, 'm, &'m RouteHandler>, AppError>
     where
         'p: 'm,
     {
-        // println!("============={:?}", &path);
         let Ok(ret) = self.router.at(path) else {
             return Err(AppError::RouterPathNotFound(path.to_string()));
         };
 
-        // println!("============={:?}", ret);
-
-        let s = match method {
-            Method::GET => ret.value.get.as_deref(),
-            Method::HEAD => ret.value.get.as_deref(),
-            Method::DELETE => ret.value.get.as_deref(),
-            Method::OPTIONS => ret.value.get.as_deref(),
-            Method::PATCH => ret.value.get.as_deref(),
-            Method::POST => ret.value.get.as_deref(),
-            Method::PUT => ret.value.get.as_deref(),
-            Method::TRACE => ret.value.get.as_deref(),
-            Method::CONNECT => ret.value.get.as_deref(),
-            _ => None,
-        }
-        .ok_or_else(|| AppError::RouterMethodNotAllow(method))?;
+        let s = ret
+            .value
+            .slot(&method)
+            .ok_or_else(|| AppError::RouterMethodNotAllow(method, ret.value.allow()))?;
 
         Ok(Match {
             value: s,
@@ -145,29 +326,93 @@ mod tests {
         let config = include_str!("../fixtures/config.yml");
         let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
         let code = "".to_string();
-        let router = SwappableAppRouter::new(code, config.routes).unwrap();
+        let router = SwappableAppRouter::new(code, config).unwrap();
         let app_router = router.load();
         let matched = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(matched.value, "hello");
+        assert_eq!(matched.value.name, "hello");
         assert_eq!(matched.params.get("id"), Some("1"));
     }
     #[test]
+    fn app_router_match_by_method_should_work() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::new("".to_string(), config).unwrap();
+        let app_router = router.load();
+        let matched = app_router.match_it(Method::POST, "/api/hello/1").unwrap();
+        assert_eq!(matched.value.name, "hello2");
+        let matched = app_router.match_it(Method::POST, "/api/world/1").unwrap();
+        assert_eq!(matched.value.name, "hello4");
+        let matched = app_router.match_it(Method::HEAD, "/api/hello/1").unwrap();
+        assert_eq!(matched.value.name, "hello");
+
+        let Err(AppError::RouterMethodNotAllow(method, allow)) =
+            app_router.match_it(Method::DELETE, "/api/hello/1")
+        else {
+            panic!("DELETE should not be allowed");
+        };
+        assert_eq!(method, Method::DELETE);
+        assert_eq!(allow, "GET, HEAD, POST, OPTIONS");
+        assert!(matches!(
+            app_router.match_it(Method::OPTIONS, "/api/hello/1"),
+            Err(AppError::RouterMethodNotAllow(Method::OPTIONS, _))
+        ));
+        assert!(matches!(
+            app_router.match_it(Method::GET, "/not/found/path"),
+            Err(AppError::RouterPathNotFound(_))
+        ));
+    }
+    #[test]
+    fn app_router_with_duplicate_method_should_fail() {
+        let config = r#"
+            name: dino-test
+            routes:
+              /api/hello:
+                - method: GET
+                  handler: hello
+                - method: get
+                  handler: hello2
+        "#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let Err(e) = SwappableAppRouter::new("".to_string(), config) else {
+            panic!("duplicate handlers should be rejected");
+        };
+        assert_eq!(e.to_string(), "Duplicate GET handler for /api/hello: hello");
+    }
+    #[test]
+    fn app_router_timeout_should_fallback_to_project_default() {
+        let config = include_str!("../fixtures/config.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::new("".to_string(), config).unwrap();
+        let app_router = router.load();
+        let matched = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
+        assert_eq!(matched.value.timeout, Duration::from_millis(500));
+        let matched = app_router.match_it(Method::GET, "/api/world/1").unwrap();
+        assert_eq!(matched.value.timeout, Duration::from_secs(5));
+
+        let config = include_str!("../fixtures/config1.yml");
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        router.swap("".to_string(), config).unwrap();
+        let app_router = router.load();
+        let matched = app_router.match_it(Method::GET, "/api/world/1").unwrap();
+        assert_eq!(matched.value.timeout, DEFAULT_HANDLER_TIMEOUT);
+    }
+    #[test]
     fn app_router_swap_should_work() {
         let config = include_str!("../fixtures/config.yml");
         let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
         let code = "".to_string();
-        let router = SwappableAppRouter::new(code, config.routes).unwrap();
+        let router = SwappableAppRouter::new(code, config).unwrap();
         let app_router = router.load();
         let matched = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(matched.value, "hello");
+        assert_eq!(matched.value.name, "hello");
         assert_eq!(matched.params.get("id"), Some("1"));
 
         let new_config = include_str!("../fixtures/config1.yml");
         let new_config: ProjectConfig = serde_yaml::from_str(new_config).unwrap();
         let code = "".to_string();
-        router.swap(code, new_config.routes).unwrap();
+        router.swap(code, new_config).unwrap();
         let app_router = router.load();
         let matched = app_router.match_it(Method::GET, "/api/goodbye/2").unwrap();
-        assert_eq!(matched.value, "handler1");
+        assert_eq!(matched.value.name, "handler1");
     }
 }
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{JsBody, JsPool, ProjectSchedule, Req};
+
+//header telling a handler which cron expression triggered it
+pub const SCHEDULE_HEADER: &str = "x-dino-schedule";
+
+//handlers of a project with a scheduled run in progress, shared across swaps so the new code
+//doesn't start a run while the old one is still busy with the same handler
+pub(crate) type Running = Arc<Mutex<HashSet<String>>>;
+
+//runs the schedules of a project on the pool of its code, the schedules stop once it is dropped,
+//a run in progress is finished first
+pub struct Scheduler {
+    stop: watch::Sender<()>,
+}
+
+//marks a handler as running until dropped
+struct RunGuard {
+    running: Running,
+    handler: String,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        project: &str,
+        schedules: Vec<ProjectSchedule>,
+        default_timeout: Duration,
+        pool: Arc<JsPool>,
+        running: Running,
+    ) -> Result<Self> {
+        let (stop_tx, stop) = watch::channel(());
+        if !schedules.is_empty() {
+            let rt = tokio::runtime::Handle::try_current()
+                .map_err(|_| anyhow!("Schedules of {} need a tokio runtime", project))?;
+            for schedule in schedules {
+                let timeout = schedule.timeout.unwrap_or(default_timeout);
+                rt.spawn(run_schedule(
+                    project.to_string(),
+                    schedule,
+                    timeout,
+                    pool.clone(),
+                    running.clone(),
+                    stop.clone(),
+                ));
+            }
+        }
+        Ok(Self { stop: stop_tx })
+    }
+
+    //no new run is started once stopped, same as dropping the scheduler
+    pub fn stop(&self) {
+        self.stop.send_replace(());
+    }
+}
+
+impl RunGuard {
+    fn acquire(running: &Running, handler: &str) -> Option<Self> {
+        let mut set = running.lock().unwrap_or_else(|e| e.into_inner());
+        set.insert(handler.to_string()).then(|| Self {
+            running: running.clone(),
+            handler: handler.to_string(),
+        })
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        let mut set = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        set.remove(&self.handler);
+    }
+}
+
+//a run is awaited before the next occurrence is computed, occurrences missed meanwhile are skipped
+async fn run_schedule(
+    project: String,
+    schedule: ProjectSchedule,
+    timeout: Duration,
+    pool: Arc<JsPool>,
+    running: Running,
+    mut stop: watch::Receiver<()>,
+) {
+    let handler = schedule.handler.as_str();
+    loop {
+        let now = Utc::now();
+        let next = match schedule.cron.find_next_occurrence(&now, false) {
+            Ok(next) => next,
+            Err(e) => {
+                warn!(project = %project, handler = %handler, "Schedule stopped: {}", e);
+                return;
+            }
+        };
+        let wait = (next - now).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = stop.changed() => return,
+        }
+
+        let Some(_guard) = RunGuard::acquire(&running, handler) else {
+            warn!(project = %project, handler = %handler, "Previous run still in progress, skipped");
+            continue;
+        };
+        let start = Instant::now();
+        match pool
+            .run_schedule(handler, schedule_req(&schedule), timeout)
+            .await
+        {
+            Ok(res) => info!(
+                project = %project,
+                handler = %handler,
+                "Schedule finished with status {} in {:?}",
+                res.status,
+                start.elapsed()
+            ),
+            Err(e) => warn!(
+                project = %project,
+                handler = %handler,
+                "Schedule failed after {:?}: {}",
+                start.elapsed(),
+                e
+            ),
+        }
+    }
+}
+
+//the payload is sent as the json body of a POST request
+fn schedule_req(schedule: &ProjectSchedule) -> Req {
+    let mut headers = vec![(
+        SCHEDULE_HEADER.to_string(),
+        schedule.cron.as_str().to_string(),
+    )];
+    let body = schedule.payload.as_ref().map(|payload| {
+        headers.push(("content-type".to_string(), "application/json".to_string()));
+        JsBody::from(payload.to_string())
+    });
+    Req::builder()
+        .method("POST")
+        .url("/")
+        .headers(headers.into_iter().collect())
+        .body(body)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PoolConfig, ProjectConfig, ResBody};
+
+    const CODE: &str = r#"
+        (function(){
+            let runs = [];
+            async function tick(req){
+                runs.push(JSON.parse(req.text()).n);
+            }
+            async function report(req){
+                return { status: 200, headers: {}, body: JSON.stringify(runs) };
+            }
+            return { tick, report };
+        })();
+    "#;
+
+    fn schedules(yaml: &str) -> Vec<ProjectSchedule> {
+        let config: ProjectConfig = serde_yaml::from_str(yaml).unwrap();
+        config.schedules
+    }
+
+    async fn report(pool: &JsPool) -> String {
+        let req = Req::builder().method("GET").url("/").build();
+        let res = pool
+            .run("report", req, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let Some(ResBody::Bytes(body)) = res.body else {
+            panic!("report should return a body");
+        };
+        String::from_utf8(body.0.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn scheduler_should_work() {
+        let config = PoolConfig {
+            workers: Some(1),
+            ..Default::default()
+        };
+        let pool = Arc::new(JsPool::new(CODE, &config, Default::default()).unwrap());
+        let schedules = schedules(
+            r#"
+            name: dino-test
+            routes: {}
+            schedules:
+              - cron: "* * * * * *"
+                handler: tick
+                payload:
+                  n: 1
+            "#,
+        );
+        let scheduler = Scheduler::new(
+            "dino-test",
+            schedules,
+            Duration::from_secs(5),
+            pool.clone(),
+            Running::default(),
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(2300)).await;
+        drop(scheduler);
+
+        let runs: Vec<u32> = serde_json::from_str(&report(&pool).await).unwrap();
+        assert!(runs.len() >= 2, "{runs:?}");
+        assert!(runs.iter().all(|n| *n == 1), "{runs:?}");
+
+        //no more runs once the scheduler is dropped
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let after: Vec<u32> = serde_json::from_str(&report(&pool).await).unwrap();
+        assert_eq!(after.len(), runs.len());
+    }
+
+    #[test]
+    fn run_guard_should_prevent_overlap() {
+        let running = Running::default();
+        let guard = RunGuard::acquire(&running, "cleanup").unwrap();
+        assert!(RunGuard::acquire(&running, "cleanup").is_none());
+        assert!(RunGuard::acquire(&running, "warm").is_some());
+        drop(guard);
+        assert!(RunGuard::acquire(&running, "cleanup").is_some());
+    }
+
+    #[test]
+    fn scheduler_without_runtime_should_fail() {
+        let pool = Arc::new(JsPool::new(CODE, &PoolConfig::default(), Default::default()).unwrap());
+        let schedules = schedules(
+            r#"
+            name: dino-test
+            routes: {}
+            schedules:
+              - cron: "0 3 * * *"
+                handler: tick
+            "#,
+        );
+        let ret = Scheduler::new(
+            "dino-test",
+            schedules,
+            Duration::from_secs(5),
+            pool.clone(),
+            Running::default(),
+        );
+        assert!(ret.is_err());
+        assert!(Scheduler::new(
+            "dino-test",
+            vec![],
+            Duration::from_secs(5),
+            pool,
+            Running::default()
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+use tracing::warn;
+
+//time given to in-flight requests and shutdown hooks once a signal is received
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+//set once the server starts draining, the handlers reject new work from then on
+#[derive(Debug, Clone, Default)]
+pub struct Drain {
+    started: Arc<OnceLock<Instant>>,
+    notify: Arc<Notify>,
+}
+
+impl Drain {
+    //returns false if the server was already draining
+    pub fn start(&self) -> bool {
+        let started = self.started.set(Instant::now()).is_ok();
+        if started {
+            self.notify.notify_waiters();
+        }
+        started
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.started.get().is_some()
+    }
+
+    //resolves with the time the drain started
+    pub async fn started(&self) -> Instant {
+        loop {
+            //registered before checking, so a concurrent start can't be missed
+            let notified = self.notify.notified();
+            if let Some(started) = self.started.get() {
+                return *started;
+            }
+            notified.await;
+        }
+    }
+}
+
+//resolves on SIGINT (ctrl-c) or, on unix, SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Fail to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Fail to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_should_work() {
+        let drain = Drain::default();
+        assert!(!drain.is_draining());
+        let waiter = {
+            let drain = drain.clone();
+            tokio::spawn(async move { drain.started().await })
+        };
+        tokio::task::yield_now().await;
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert!(drain.is_draining());
+        let started = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(drain.started().await, started);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+
+//file name quickjs gives to the frames of the code evaluated by the engines
+const BUNDLE_FILE: &str = "eval_script";
+
+//maps the bundle back to the files it was built from, written next to it by `dino build`
+#[derive(Debug)]
+pub struct SourceMap(sourcemap::SourceMap);
+
+impl SourceMap {
+    pub fn parse(json: &str) -> Result<Self> {
+        Ok(Self(sourcemap::SourceMap::from_slice(json.as_bytes())?))
+    }
+
+    //`at hello (eval_script:1:250)` becomes `at hello (main.ts:3:10)`, frames of other files or
+    //without a mapping are kept as is
+    pub fn remap_stack(&self, stack: &str) -> String {
+        stack
+            .split_inclusive('\n')
+            .map(|frame| self.remap_frame(frame).unwrap_or_else(|| frame.to_string()))
+            .collect()
+    }
+
+    fn remap_frame(&self, frame: &str) -> Option<String> {
+        let start = frame.find(BUNDLE_FILE)?;
+        let rest = &frame[start + BUNDLE_FILE.len()..];
+        let location = rest.strip_prefix(':')?;
+        let end = location
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(location.len());
+        let (line, col) = location[..end].split_once(':')?;
+        let (line, col) = (line.parse::<u32>().ok()?, col.parse::<u32>().ok()?);
+        //quickjs counts lines and columns from 1, source maps from 0
+        let token = self
+            .0
+            .lookup_token(line.checked_sub(1)?, col.saturating_sub(1))?;
+        let original = format!(
+            "{}:{}:{}",
+            token.get_source()?,
+            token.get_src_line() + 1,
+            token.get_src_col() + 1
+        );
+        Some(format!(
+            "{}{}{}",
+            &frame[..start],
+            original,
+            &location[end..]
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_stack_should_work() {
+        let mut builder = sourcemap::SourceMapBuilder::new(None);
+        builder.add(0, 0, 0, 0, Some("main.ts"), None, false);
+        builder.add(0, 249, 2, 9, Some("main.ts"), None, false);
+        builder.add(0, 300, 7, 2, Some("lib.ts"), None, false);
+        let map = SourceMap(builder.into_sourcemap());
+
+        let stack = "    at hello (eval_script:1:250)\n    at parse (eval_script:1:305)\n    at <anonymous> (native)\n";
+        assert_eq!(
+            map.remap_stack(stack),
+            "    at hello (main.ts:3:10)\n    at parse (lib.ts:8:3)\n    at <anonymous> (native)\n"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+use axum::{
+    body::Body,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+};
+use rquickjs::{Array, Ctx, Function, Object, Value};
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt as _};
+use tracing::{warn, Instrument as _, Span};
+
+use crate::stream::{js_error_message, Streams};
+
+//number of events buffered for a slow client before `send` starts to drop them
+const EVENT_BUFFER: usize = 64;
+
+//wraps the native send and close into the `events` object given to sse handlers
+const EVENT_SINK: &str = r#"
+(function (send, close) {
+    const listeners = [];
+    let closed = false;
+    const sink = {
+        get closed() {
+            return closed;
+        },
+        send(event, data) {
+            if (closed) {
+                return false;
+            }
+            if (typeof data !== "string") {
+                data = JSON.stringify(data);
+            }
+            return send(event == null ? null : String(event), data ?? "");
+        },
+        close() {
+            close();
+        },
+        onClose(listener) {
+            if (closed) {
+                listener();
+            } else {
+                listeners.push(listener);
+            }
+        },
+    };
+    const onClosed = () => {
+        closed = true;
+        for (const listener of listeners.splice(0)) {
+            listener();
+        }
+    };
+    return [sink, onClosed];
+})
+"#;
+
+//the events are sent as they come, with a comment every now and then to keep the connection open
+pub(crate) fn into_body(rx: mpsc::Receiver<Event>) -> Body {
+    let events = ReceiverStream::new(rx).map(Ok::<_, Infallible>);
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+        .into_body()
+}
+
+//native side of a sink, the sender is dropped once the sink is closed which ends the response
+#[derive(Default)]
+struct SinkState {
+    tx: RefCell<Option<mpsc::Sender<Event>>>,
+    close: Notify,
+}
+
+//creates the `events` object, it stays open until the client goes away, the js closes it
+//or the engine shuts down, the `onClose` listeners are called in all cases
+pub(crate) fn event_sink<'js>(
+    ctx: &Ctx<'js>,
+) -> rquickjs::Result<(Object<'js>, mpsc::Receiver<Event>)> {
+    let streams = Streams::from_ctx(ctx)?;
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+    let state = Rc::new(SinkState::default());
+    state.tx.replace(Some(tx.clone()));
+
+    let sender = state.clone();
+    let send = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, event: Option<String>, data: String| {
+            let Some(tx) = sender.tx.borrow().clone() else {
+                return Ok::<_, rquickjs::Error>(false);
+            };
+            let event =
+                to_event(event, &data).map_err(|msg| rquickjs::Exception::throw_type(&ctx, msg))?;
+            match tx.try_send(event) {
+                Ok(()) => Ok(true),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Client is too slow, dropping event");
+                    Ok(false)
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => Ok(false),
+            }
+        },
+    )?;
+    let closer = state.clone();
+    let close = Function::new(ctx.clone(), move || closer.close.notify_one())?;
+
+    let wrap: Function = ctx.eval(EVENT_SINK)?;
+    let pair: Array = wrap.call((send, close))?;
+    let (sink, on_closed): (Object, Function) = (pair.get(0)?, pair.get(1)?);
+
+    streams.begin();
+    let inner = ctx.clone();
+    ctx.spawn(
+        async move {
+            tokio::select! {
+                _ = tx.closed() => {}
+                _ = state.close.notified() => {}
+                _ = streams.closed() => {}
+            }
+            state.tx.replace(None);
+            drop(tx);
+            if let Err(e) = on_closed.call::<_, Value>(()) {
+                warn!(
+                    "Event sink close listener failed: {}",
+                    js_error_message(&inner, e)
+                );
+            }
+            streams.end();
+        }
+        //outlives the job, keeps logging under the request it was opened by
+        .instrument(Span::current()),
+    );
+    Ok((sink, rx))
+}
+
+//sse fields can't hold carriage returns, line breaks in data are sent as multiple data lines
+fn to_event(name: Option<String>, data: &str) -> Result<Event, &'static str> {
+    let event = match name {
+        Some(name) if name.contains(['\r', '\n']) => {
+            return Err("event name can't contain line breaks")
+        }
+        Some(name) if !name.is_empty() => Event::default().event(name),
+        _ => Event::default(),
+    };
+    Ok(event.data(data.replace("\r\n", "\n").replace('\r', "\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::to_bytes;
+
+    use crate::{JsEngine, Req, ResBody};
+
+    const CODE: &str = r#"
+        (function(){
+            async function events(req, events){
+                events.send("greeting", "hello\nworld");
+                events.send(null, { n: 1 });
+                let ticks = 0;
+                const id = setInterval(() => {
+                    events.send("tick", String(++ticks));
+                    if (ticks === 2) {
+                        events.close();
+                    }
+                }, 5);
+                events.onClose(() => {
+                    clearInterval(id);
+                    globalThis.closed = events.send("late", "dropped");
+                });
+            }
+            async function closed(req){
+                return { status: 200, headers: {}, body: String(globalThis.closed) };
+            }
+            async function forever(req, events){
+                events.send("open", "");
+            }
+            return { events, closed, forever };
+        })();
+    "#;
+
+    fn req() -> Req {
+        Req::builder().method("GET").url("/events").build()
+    }
+
+    #[tokio::test]
+    async fn event_sink_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        let res = engine.run_sse("events", req(), timeout).await.unwrap();
+        let Some(ResBody::Events(rx)) = res.body else {
+            panic!("sse handler should answer with events");
+        };
+        let body = super::into_body(rx);
+        let (_, body) = tokio::join!(engine.drain_streams(), to_bytes(body, usize::MAX));
+        assert_eq!(
+            body.unwrap(),
+            "event: greeting\ndata: hello\ndata: world\n\n\
+             data: {\"n\":1}\n\n\
+             event: tick\ndata: 1\n\n\
+             event: tick\ndata: 2\n\n"
+        );
+
+        let res = engine.run("closed", req(), timeout).await.unwrap();
+        assert_eq!(res.body, Some("false".into()));
+    }
+
+    #[tokio::test]
+    async fn event_sink_should_close_with_engine() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let timeout = Duration::from_secs(1);
+        let res = engine.run_sse("forever", req(), timeout).await.unwrap();
+        let Some(ResBody::Events(mut rx)) = res.body else {
+            panic!("sse handler should answer with events");
+        };
+        assert!(rx.recv().await.is_some());
+        engine.close_streams().await;
+        assert!(rx.recv().await.is_none());
+    }
+}
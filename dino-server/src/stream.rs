@@ -0,0 +1,390 @@
+use std::{cell::Cell, fmt, future::Future, io, rc::Rc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    response::sse::Event,
+};
+use rquickjs::{
+    function::This, promise::MaybePromise, Ctx, FromJs, Function, JsLifetime, Object, Value,
+};
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{warn, Instrument as _, Span};
+
+use crate::{sse, ws::WsChannel, Interrupt, JsBody};
+
+//number of chunks buffered between the js engine and the connection
+const STREAM_BUFFER: usize = 16;
+
+//turns a ReadableStream or an async iterable into an iterator, anything else into null
+const TO_ITERATOR: &str = r#"
+(function (body) {
+    if (body && typeof body.getReader === "function") {
+        const reader = body.getReader();
+        return {
+            next: () => reader.read(),
+            return: () => reader.cancel && reader.cancel(),
+        };
+    }
+    if (body && typeof body[Symbol.asyncIterator] === "function") {
+        return body[Symbol.asyncIterator]();
+    }
+    return null;
+})
+"#;
+
+type Chunk = Result<Bytes, io::Error>;
+
+//body of a response, either fully buffered or streamed while the js produces the chunks
+pub enum ResBody {
+    Bytes(JsBody),
+    Stream(mpsc::Receiver<Chunk>),
+    //events sent by a sse handler through its event sink
+    Events(mpsc::Receiver<Event>),
+    //a websocket opened by the js, bridged to the connection once upgraded
+    Socket(WsChannel),
+}
+
+impl<T: Into<Bytes>> From<T> for ResBody {
+    fn from(bytes: T) -> Self {
+        Self::Bytes(bytes.into().into())
+    }
+}
+
+impl From<ResBody> for Body {
+    fn from(body: ResBody) -> Self {
+        match body {
+            ResBody::Bytes(body) => Body::from(body.0),
+            ResBody::Stream(rx) => Body::from_stream(ReceiverStream::new(rx)),
+            ResBody::Events(rx) => sse::into_body(rx),
+            ResBody::Socket(_) => Body::empty(),
+        }
+    }
+}
+
+//streams and sockets are never equal, they can only be consumed once
+impl PartialEq for ResBody {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bytes(a), Self::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for ResBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(body) => f.debug_tuple("Bytes").field(body).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+            Self::Events(_) => f.write_str("Events"),
+            Self::Socket(_) => f.write_str("Socket"),
+        }
+    }
+}
+
+impl serde::Serialize for ResBody {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Bytes(body) => body.serialize(serializer),
+            Self::Stream(_) | Self::Events(_) | Self::Socket(_) => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'js> FromJs<'js> for ResBody {
+    fn from_js(ctx: &Ctx<'js>, value: Value<'js>) -> rquickjs::Result<Self> {
+        if value.is_object() {
+            let to_iterator: Function = ctx.eval(TO_ITERATOR)?;
+            if let Some(iter) = to_iterator.call::<_, Option<Object>>((value.clone(),))? {
+                return Ok(Self::Stream(Streams::spawn(ctx, iter)?));
+            }
+        }
+        Ok(Self::Bytes(JsBody::from_js(ctx, value)?))
+    }
+}
+
+//keeps track of the streams and event sinks of a runtime, stored as the context userdata
+#[derive(Clone, Default)]
+pub(crate) struct Streams {
+    active: Rc<Cell<usize>>,
+    done: Rc<Notify>,
+    //set once the engine is going away, every open stream must finish
+    closing: Rc<Cell<bool>>,
+    close: Rc<Notify>,
+    //how long the js may take to produce the next chunk
+    timeout: Rc<Cell<Duration>>,
+    interrupt: Interrupt,
+}
+
+unsafe impl<'js> JsLifetime<'js> for Streams {
+    type Changed<'to> = Streams;
+}
+
+impl Streams {
+    pub(crate) fn new(interrupt: Interrupt) -> Self {
+        Self {
+            interrupt,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_timeout(&self, timeout: Duration) {
+        self.timeout.set(timeout);
+    }
+
+    //timeout of the handler being run, a stream keeps the one of the handler that opened it
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout.get()
+    }
+
+    //runs js called back by a stream under its timeout, Err(timeout) if it took too long
+    pub(crate) async fn guard<F: Future>(
+        &self,
+        timeout: Duration,
+        fut: F,
+    ) -> Result<F::Output, Duration> {
+        let deadline = self.interrupt.arm(timeout);
+        let ret = tokio::time::timeout(timeout, fut).await;
+        if self.interrupt.disarm(deadline) {
+            return Err(timeout);
+        }
+        ret.map_err(|_| timeout)
+    }
+
+    //some stream or event sink is still open, the runtime must be driven for it
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.get() > 0
+    }
+
+    //resolves once every stream of the runtime is finished, the runtime must be driven meanwhile
+    pub(crate) async fn wait(&self) {
+        while self.active.get() > 0 {
+            self.done.notified().await;
+        }
+    }
+
+    //asks every open stream to finish, `wait` tells when they are done
+    pub(crate) fn close(&self) {
+        self.closing.set(true);
+        self.close.notify_waiters();
+    }
+
+    pub(crate) async fn closed(&self) {
+        while !self.closing.get() {
+            self.close.notified().await;
+        }
+    }
+
+    pub(crate) fn from_ctx(ctx: &Ctx) -> rquickjs::Result<Self> {
+        ctx.userdata::<Streams>()
+            .map(|s| s.clone())
+            .ok_or_else(|| rquickjs::Error::new_from_js("object", "stream"))
+    }
+
+    pub(crate) fn begin(&self) {
+        self.active.set(self.active.get() + 1);
+    }
+
+    pub(crate) fn end(&self) {
+        self.active.set(self.active.get() - 1);
+        self.done.notify_one();
+    }
+
+    fn spawn<'js>(ctx: &Ctx<'js>, iter: Object<'js>) -> rquickjs::Result<mpsc::Receiver<Chunk>> {
+        let streams = Self::from_ctx(ctx)?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let timeout = streams.timeout();
+        streams.begin();
+        let inner = ctx.clone();
+        ctx.spawn(
+            async move {
+                streams.pump(inner, iter, tx, timeout).await;
+                streams.end();
+            }
+            .instrument(Span::current()),
+        );
+        Ok(rx)
+    }
+
+    async fn pump<'js>(
+        &self,
+        ctx: Ctx<'js>,
+        iter: Object<'js>,
+        tx: mpsc::Sender<Chunk>,
+        timeout: Duration,
+    ) {
+        loop {
+            let deadline = self.interrupt.arm(timeout);
+            let ret = tokio::select! {
+                ret = tokio::time::timeout(timeout, next_chunk(&ctx, &iter)) => Some(ret),
+                _ = self.closed() => None,
+            };
+            let interrupted = self.interrupt.disarm(deadline);
+            let error = match ret {
+                _ if interrupted => format!("next chunk timed out after {:?}", timeout),
+                None => "js engine is shutting down".to_string(),
+                Some(Ok(Ok(Some(chunk)))) => {
+                    match tokio::time::timeout(timeout, tx.send(Ok(chunk))).await {
+                        Ok(Ok(())) => continue,
+                        //the client went away, let the js clean up
+                        Ok(Err(_)) => {
+                            close_iterator(&ctx, &iter);
+                            return;
+                        }
+                        //the client stopped reading, it doesn't get to keep the stream open
+                        Err(_) => {
+                            format!("client did not take the next chunk within {:?}", timeout)
+                        }
+                    }
+                }
+                Some(Ok(Ok(None))) => return,
+                Some(Ok(Err(e))) => js_error_message(&ctx, e),
+                Some(Err(_)) => format!("next chunk timed out after {:?}", timeout),
+            };
+            warn!("Streaming response body failed: {}", error);
+            close_iterator(&ctx, &iter);
+            //the error aborts the response so the client knows the body is incomplete
+            let _ = tokio::time::timeout(timeout, tx.send(Err(io::Error::other(error)))).await;
+            return;
+        }
+    }
+}
+
+async fn next_chunk<'js>(ctx: &Ctx<'js>, iter: &Object<'js>) -> rquickjs::Result<Option<Bytes>> {
+    let next: Function = iter.get("next")?;
+    let ret: MaybePromise = next.call((This(iter.clone()),))?;
+    let ret: Object = ret.into_future().await?;
+    if ret.get::<_, Option<bool>>("done")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    let chunk = JsBody::from_js(ctx, ret.get("value")?)?;
+    Ok(Some(chunk.0))
+}
+
+fn close_iterator<'js>(ctx: &Ctx<'js>, iter: &Object<'js>) {
+    if let Ok(Some(close)) = iter.get::<_, Option<Function>>("return") {
+        if let Err(e) = close.call::<_, Value>((This(iter.clone()),)) {
+            warn!("Fail to close stream: {}", js_error_message(ctx, e));
+        }
+    }
+}
+
+pub(crate) fn js_error_message(ctx: &Ctx, e: rquickjs::Error) -> String {
+    match e {
+        rquickjs::Error::Exception => format!("{:?}", ctx.catch()),
+        e => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsEngine, Req};
+
+    const CODE: &str = r#"
+        (function(){
+            async function* rows() {
+                yield "name,goals\n";
+                for (let i = 0; i < 3; i++) {
+                    await new Promise((resolve) => setTimeout(resolve, 1));
+                    yield new Uint8Array([112, 48 + i, 44, 48 + i, 10]);
+                }
+            }
+            async function csv(req){
+                return { status: 200, headers: {}, body: rows() };
+            }
+            async function reader(req){
+                let i = 0;
+                const body = {
+                    getReader: () => ({
+                        read: async () => i < 2
+                            ? { done: false, value: JSON.stringify({ i: i++ }) + "\n" }
+                            : { done: true },
+                    }),
+                };
+                return { status: 200, headers: {}, body };
+            }
+            async function broken(req){
+                async function* chunks() {
+                    yield "first";
+                    throw new Error("boom");
+                }
+                return { status: 200, headers: {}, body: chunks() };
+            }
+            async function endless(req){
+                async function* chunks() {
+                    while (true) {
+                        yield "more";
+                    }
+                }
+                return { status: 200, headers: {}, body: chunks() };
+            }
+            return { csv, reader, broken, endless };
+        })();
+    "#;
+
+    async fn collect(engine: &JsEngine, name: &str) -> Vec<Chunk> {
+        let req = Req::builder().method("GET").url("/").build();
+        let res = engine.run(name, req, Duration::from_secs(1)).await.unwrap();
+        let Some(ResBody::Stream(mut rx)) = res.body else {
+            panic!("body should be streamed");
+        };
+        let read = async move {
+            let mut chunks = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                chunks.push(chunk);
+            }
+            chunks
+        };
+        let (_, chunks) = tokio::join!(engine.drain_streams(), read);
+        chunks
+    }
+
+    #[tokio::test]
+    async fn async_iterator_body_should_stream() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let chunks = collect(&engine, "csv").await;
+        let body: Vec<u8> = chunks
+            .into_iter()
+            .flat_map(|c| c.unwrap().to_vec())
+            .collect();
+        assert_eq!(body, b"name,goals\np0,0\np1,1\np2,2\n");
+    }
+
+    #[tokio::test]
+    async fn readable_stream_body_should_stream() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let chunks = collect(&engine, "reader").await;
+        let chunks: Vec<_> = chunks.into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks, ["{\"i\":0}\n", "{\"i\":1}\n"]);
+    }
+
+    #[tokio::test]
+    async fn broken_stream_should_abort_body() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let chunks = collect(&engine, "broken").await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap(), "first");
+        assert!(chunks[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn unread_stream_should_abort_body() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let req = Req::builder().method("GET").url("/").build();
+        let timeout = Duration::from_millis(50);
+        let res = engine.run("endless", req, timeout).await.unwrap();
+        let Some(ResBody::Stream(mut rx)) = res.body else {
+            panic!("body should be streamed");
+        };
+        let drained = tokio::time::timeout(Duration::from_secs(1), engine.drain_streams()).await;
+        assert!(drained.is_ok());
+        let mut chunks = 0;
+        while let Some(chunk) = rx.recv().await {
+            assert!(chunk.is_ok());
+            chunks += 1;
+        }
+        assert_eq!(chunks, STREAM_BUFFER);
+    }
+}
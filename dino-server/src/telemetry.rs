@@ -0,0 +1,121 @@
+use anyhow::Result;
+use axum::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig as _};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Tracer, TracerProvider},
+    Resource,
+};
+use tracing::{warn, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt as _};
+use tracing_subscriber::registry::LookupSpan;
+
+//the otlp/grpc port of a collector running next to the server
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+const SERVICE_NAME: &str = "dino";
+
+//exports the spans to an otlp collector, shut it down before exiting so the last batch is sent
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Telemetry {
+    //spans are exported in batches from the tokio runtime it is called in
+    pub fn otlp(endpoint: &str) -> Result<Self> {
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                SERVICE_NAME,
+            )]))
+            .build();
+        //the context of the caller comes in the w3c traceparent and tracestate headers
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(Self { provider })
+    }
+
+    //turns the tracing spans into otel spans, to be added to the subscriber
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(SERVICE_NAME))
+    }
+
+    //flushing blocks until the collector answers, so it is kept off the async threads
+    pub async fn shutdown(self) {
+        let provider = self.provider;
+        let ret = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        match ret {
+            Ok(Ok(())) | Ok(Err(TraceError::TracerProviderAlreadyShutdown)) => {}
+            Ok(Err(e)) => warn!("Fail to flush the spans: {}", e),
+            Err(e) => warn!("Fail to flush the spans: {}", e),
+        }
+    }
+}
+
+//continues the trace of the caller if the request carries one, a no-op without otlp export
+pub(crate) fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|p| p.extract(&Headers(headers)));
+    span.set_parent(parent);
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt as _;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    #[test]
+    fn remote_parent_should_be_honored() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder().build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                    .parse()
+                    .unwrap(),
+            );
+            let span = info_span!("request");
+            set_remote_parent(&span, &headers);
+            let cx = span.context();
+            let trace_id = cx.span().span_context().trace_id();
+            assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+
+            //without one the request starts a trace of its own
+            let span = info_span!("request");
+            set_remote_parent(&span, &HeaderMap::new());
+            let cx = span.context();
+            let other = cx.span().span_context().trace_id();
+            assert_ne!(other, trace_id);
+        });
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwapOption;
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use crate::{JsBundle, ProjectConfig, SwappableAppRouter};
+
+//routers by host, a host is either an exact name or a pattern like `*.tenant.example` matching
+//any single label in front of `tenant.example`, both kinds live in the same map so a lookup is
+//at most two hash lookups plus the fallback
+#[derive(Default)]
+pub struct Tenants {
+    pub(crate) hosts: DashMap<String, SwappableAppRouter>,
+    //host of the tenant serving the requests no other tenant claims
+    fallback: ArcSwapOption<String>,
+}
+
+impl Tenants {
+    //fails if the host is claimed by another tenant, registering the same router again is fine
+    pub fn register(&self, host: &str, router: SwappableAppRouter) -> Result<()> {
+        self.claim(normalize_pattern(host)?, &router).map(|_| ())
+    }
+
+    //true if the host was free and is now the router's, the entry is checked and taken at once
+    fn claim(&self, host: String, router: &SwappableAppRouter) -> Result<bool> {
+        match self.hosts.entry(host) {
+            Entry::Occupied(entry) if !Arc::ptr_eq(&entry.get().inners, &router.inners) => Err(
+                anyhow!("Host {} is already claimed by another tenant", entry.key()),
+            ),
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                router.set_primary_host(entry.key());
+                entry.insert(router.clone());
+                Ok(true)
+            }
+        }
+    }
+
+    //swaps the tenant of the host to the new code or registers a new one, true if it was created,
+    //the hosts listed in the config are registered to it as well and the ones it no longer lists
+    //are dropped, nothing changes if one of them is claimed by another tenant
+    pub fn deploy(
+        &self,
+        host: &str,
+        bundle: impl Into<JsBundle>,
+        config: ProjectConfig,
+    ) -> Result<bool> {
+        let host = normalize_pattern(host)?;
+        let mut hosts = vec![host.clone()];
+        for alias in &config.hosts {
+            let alias = normalize_pattern(alias)?;
+            if !hosts.contains(&alias) {
+                hosts.push(alias);
+            }
+        }
+        //a new router is built before any host is claimed, no shard is locked meanwhile
+        let (router, pending) = match self.get(&host) {
+            Some(router) => (router, Some((bundle, config))),
+            None => (SwappableAppRouter::new(bundle, config)?, None),
+        };
+
+        //every host is claimed before the swap, the ones taken are given back if one fails
+        let mut claimed = Vec::new();
+        for h in &hosts {
+            match self.claim(h.clone(), &router) {
+                Ok(true) => claimed.push(h.as_str()),
+                Ok(false) => {}
+                Err(e) => {
+                    self.release(&claimed, &router);
+                    return Err(e);
+                }
+            }
+        }
+        let created = pending.is_none();
+        if let Some((bundle, config)) = pending {
+            if let Err(e) = router.swap(bundle, config) {
+                self.release(&claimed, &router);
+                return Err(e);
+            }
+        }
+        self.hosts
+            .retain(|h, r| !Arc::ptr_eq(&r.inners, &router.inners) || hosts.contains(h));
+        Ok(created)
+    }
+
+    fn release(&self, hosts: &[&str], router: &SwappableAppRouter) {
+        for host in hosts {
+            self.unregister(host, router);
+        }
+    }
+
+    //every host and pattern the router is registered under
+    pub fn hosts_of(&self, router: &SwappableAppRouter) -> Vec<String> {
+        self.hosts
+            .iter()
+            .filter(|entry| Arc::ptr_eq(&entry.value().inners, &router.inners))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    pub fn remove(&self, host: &str) -> Option<SwappableAppRouter> {
+        self.hosts
+            .remove(&normalize(host))
+            .map(|(_, router)| router)
+    }
+
+    //removes the host only if it is still registered to this router
+    pub fn unregister(&self, host: &str, router: &SwappableAppRouter) -> bool {
+        self.hosts
+            .remove_if(&normalize(host), |_, r| {
+                Arc::ptr_eq(&r.inners, &router.inners)
+            })
+            .is_some()
+    }
+
+    //the router registered for the host or pattern itself, no wildcard or fallback applied
+    pub fn get(&self, host: &str) -> Option<SwappableAppRouter> {
+        self.hosts.get(&normalize(host)).map(|r| r.clone())
+    }
+
+    //the fallback doesn't need to be registered yet, it applies once it is
+    pub fn set_fallback(&self, host: Option<&str>) {
+        self.fallback.store(host.map(|h| Arc::new(normalize(h))));
+    }
+
+    //exact host first, then the wildcard of its parent domain, then the fallback tenant
+    pub fn resolve(&self, host: &str) -> Option<SwappableAppRouter> {
+        let host = normalize(strip_port(host));
+        if let Some(router) = self.hosts.get(&host) {
+            return Some(router.clone());
+        }
+        if let Some((_, parent)) = host.split_once('.') {
+            if let Some(router) = self.hosts.get(&format!("*.{}", parent)) {
+                return Some(router.clone());
+            }
+        }
+        let fallback = self.fallback.load();
+        self.hosts.get(fallback.as_deref()?).map(|r| r.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+//a wildcard is only allowed as the whole first label
+fn normalize_pattern(host: &str) -> Result<String> {
+    let host = normalize(host);
+    let name = host.strip_prefix("*.").unwrap_or(&host);
+    if name.is_empty() || name.contains('*') || name.split('.').any(str::is_empty) {
+        return Err(anyhow!("Invalid host {}", host));
+    }
+    Ok(host)
+}
+
+//ipv6 hosts keep their brackets
+pub(crate) fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> SwappableAppRouter {
+        let config: ProjectConfig =
+            serde_yaml::from_str(include_str!("../fixtures/config.yml")).unwrap();
+        SwappableAppRouter::new("(function(){ return {}; })();".to_string(), config).unwrap()
+    }
+
+    fn same(a: &SwappableAppRouter, b: &SwappableAppRouter) -> bool {
+        Arc::ptr_eq(&a.inners, &b.inners)
+    }
+
+    #[test]
+    fn tenants_resolve_should_work() {
+        let tenants = Tenants::default();
+        let (shop, blog, other) = (router(), router(), router());
+        tenants.register("shop.example", shop.clone()).unwrap();
+        tenants.register("www.shop.example", shop.clone()).unwrap();
+        tenants.register("*.blog.example", blog.clone()).unwrap();
+        tenants.register("other.example", other.clone()).unwrap();
+
+        assert!(same(&tenants.resolve("Shop.Example:8080").unwrap(), &shop));
+        assert!(same(&tenants.resolve("www.shop.example").unwrap(), &shop));
+        assert!(same(&tenants.resolve("alice.blog.example").unwrap(), &blog));
+        //a wildcard covers a single label
+        assert!(tenants.resolve("a.alice.blog.example").is_none());
+        assert!(tenants.resolve("blog.example").is_none());
+
+        tenants.set_fallback(Some("other.example"));
+        assert!(same(&tenants.resolve("127.0.0.1:8080").unwrap(), &other));
+        assert!(same(&tenants.resolve("[::1]:8080").unwrap(), &other));
+        tenants.set_fallback(None);
+        assert!(tenants.resolve("127.0.0.1").is_none());
+    }
+
+    #[test]
+    fn tenants_register_should_reject_conflicts() {
+        let tenants = Tenants::default();
+        let (a, b) = (router(), router());
+        tenants.register("shop.example", a.clone()).unwrap();
+        tenants.register("SHOP.example", a.clone()).unwrap();
+        let err = tenants.register("shop.example", b.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Host shop.example is already claimed by another tenant"
+        );
+        assert!(!tenants.unregister("shop.example", &b));
+        assert!(tenants.unregister("shop.example", &a));
+        tenants.register("shop.example", b).unwrap();
+    }
+
+    #[test]
+    fn tenants_register_should_reject_invalid_hosts() {
+        let tenants = Tenants::default();
+        for host in ["", "*", "*.", "a.*.example", "a..example", "*example.com"] {
+            assert!(tenants.register(host, router()).is_err(), "{host}");
+        }
+    }
+
+    #[test]
+    fn tenants_deploy_should_claim_every_host_or_none() {
+        let tenants = Tenants::default();
+        let blog = router();
+        tenants.register("blog.example", blog.clone()).unwrap();
+        let config = |hosts: &str| -> ProjectConfig {
+            serde_yaml::from_str(&format!("name: shop\nhosts: {}\nroutes: {{}}", hosts)).unwrap()
+        };
+        let (v1, v2) = (
+            "(function(){ return {}; })();",
+            "(function(){ return { a: 1 }; })();",
+        );
+
+        let ret = tenants.deploy(
+            "shop.example",
+            v1.to_string(),
+            config("[www.shop.example, blog.example]"),
+        );
+        assert!(ret.is_err());
+        assert!(tenants.get("shop.example").is_none());
+        assert!(tenants.get("www.shop.example").is_none());
+
+        let ret = tenants.deploy("shop.example", v1.to_string(), config("[www.shop.example]"));
+        assert!(ret.unwrap());
+        let shop = tenants.get("shop.example").unwrap();
+        let ret = tenants.deploy(
+            "shop.example",
+            v2.to_string(),
+            config("['*.shop.example', blog.example]"),
+        );
+        assert!(ret.is_err());
+        //still on the first code and hosts
+        assert_eq!(shop.load().hash, crate::build_hash(v1));
+        assert!(tenants.get("*.shop.example").is_none());
+        assert!(same(&tenants.get("www.shop.example").unwrap(), &shop));
+        assert!(same(&tenants.get("blog.example").unwrap(), &blog));
+
+        let ret = tenants.deploy("shop.example", v2.to_string(), config("['*.shop.example']"));
+        assert!(!ret.unwrap());
+        assert_eq!(shop.load().hash, crate::build_hash(v2));
+        assert!(tenants.get("www.shop.example").is_none());
+        assert!(same(&tenants.resolve("alice.shop.example").unwrap(), &shop));
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fmt::Write as _,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+tokio::task_local! {
+    //timing of the request being served, followed to the js worker running its handler
+    static SERVER_TIMING: ServerTiming;
+}
+
+//entries of the Server-Timing header, filled in by the server phases and by the handler
+#[derive(Debug, Clone, Default)]
+pub struct ServerTiming(Arc<Mutex<Vec<TimingEntry>>>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct TimingEntry {
+    name: String,
+    //milliseconds
+    dur: f64,
+    desc: Option<String>,
+}
+
+impl ServerTiming {
+    pub fn record(&self, name: impl Into<String>, dur: Duration, desc: Option<String>) {
+        self.record_ms(name, dur.as_secs_f64() * 1000.0, desc);
+    }
+
+    pub(crate) fn record_ms(&self, name: impl Into<String>, dur: f64, desc: Option<String>) {
+        let entry = TimingEntry {
+            name: name.into(),
+            dur,
+            desc,
+        };
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(entry);
+    }
+
+    pub(crate) fn current() -> Option<Self> {
+        SERVER_TIMING.try_with(|timing| timing.clone()).ok()
+    }
+
+    //makes the timing `current` for the future, e.g. across to the js worker
+    pub(crate) async fn scope<F: Future>(timing: Option<Self>, fut: F) -> F::Output {
+        match timing {
+            Some(timing) => SERVER_TIMING.scope(timing, fut).await,
+            None => fut.await,
+        }
+    }
+
+    //`route;dur=0.12, db;dur=12;desc="users", total;dur=15.3`
+    pub fn header_value(&self, total: Duration) -> String {
+        let entries = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut value = String::new();
+        for entry in entries.iter() {
+            let _ = write!(value, "{};dur={:.3}", entry.name, entry.dur);
+            if let Some(desc) = &entry.desc {
+                let desc = desc.replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = write!(value, ";desc=\"{}\"", desc);
+            }
+            value.push_str(", ");
+        }
+        let _ = write!(value, "total;dur={:.3}", total.as_secs_f64() * 1000.0);
+        value
+    }
+}
+
+//names of the entries are http tokens, as they go in the header unquoted
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn server_timing_should_work() {
+        let timing = ServerTiming::default();
+        timing.record("route", Duration::from_micros(120), None);
+        ServerTiming::scope(Some(timing.clone()), async {
+            let current = ServerTiming::current().unwrap();
+            current.record_ms("db", 12.0, Some("users \"a\"".to_string()));
+        })
+        .await;
+        assert!(ServerTiming::current().is_none());
+        assert_eq!(
+            timing.header_value(Duration::from_millis(15)),
+            "route;dur=0.120, db;dur=12.000;desc=\"users \\\"a\\\"\", total;dur=15.000"
+        );
+        assert!(is_valid_name("cache-hit"));
+        assert!(!is_valid_name("db time"));
+        assert!(!is_valid_name(""));
+    }
+}
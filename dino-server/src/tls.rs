@@ -0,0 +1,396 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::BufReader,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use axum::{
+    extract::{Host, State},
+    http::Uri,
+    response::Redirect,
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use dashmap::DashMap;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use typed_builder::TypedBuilder;
+
+use crate::Drain;
+
+//how often the pem files are checked for changes
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+//pem files of a tenant's certificate chain and private key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsCert {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, TypedBuilder)]
+pub struct TlsOptions {
+    //plain http port redirecting to https, nothing listens on http without it
+    #[builder(default)]
+    pub redirect_port: Option<u16>,
+    #[builder(default = DEFAULT_RELOAD_INTERVAL)]
+    pub reload_interval: Duration,
+}
+
+//certificates by host name, picked by the SNI of the client hello
+#[derive(Debug, Default)]
+pub struct CertStore {
+    certs: DashMap<String, LoadedCert>,
+    //used by clients not sending SNI, e.g. when connecting by ip
+    default: RwLock<Option<String>>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    source: TlsCert,
+    modified: Option<SystemTime>,
+    certified: Arc<CertifiedKey>,
+}
+
+impl TlsCert {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+        }
+    }
+
+    fn load(&self) -> Result<Arc<CertifiedKey>> {
+        let mut reader = BufReader::new(open(&self.cert)?);
+        let certs = rustls_pemfile::certs(&mut reader)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid certificate in {}", self.cert.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificate found in {}", self.cert.display()));
+        }
+        let mut reader = BufReader::new(open(&self.key)?);
+        let key = rustls_pemfile::private_key(&mut reader)
+            .with_context(|| format!("Invalid private key in {}", self.key.display()))?
+            .ok_or_else(|| anyhow!("No private key found in {}", self.key.display()))?;
+        let key = CertifiedKey::from_der(certs, key, provider())
+            .with_context(|| format!("Fail to load certificate {}", self.cert.display()))?;
+        Ok(Arc::new(key))
+    }
+
+    //latest change of either file, a reload is only attempted once it moves
+    fn modified(&self) -> Option<SystemTime> {
+        let cert = self.cert.metadata().and_then(|m| m.modified()).ok()?;
+        let key = self.key.metadata().and_then(|m| m.modified()).ok()?;
+        Some(cert.max(key))
+    }
+}
+
+impl CertStore {
+    //the first host inserted is the default one
+    pub fn insert(&self, host: impl Into<String>, source: TlsCert) -> Result<()> {
+        let host = host.into().to_ascii_lowercase();
+        let modified = source.modified();
+        let certified = source.load()?;
+        let mut default = self.default.write().unwrap_or_else(|e| e.into_inner());
+        default.get_or_insert_with(|| host.clone());
+        self.certs.insert(
+            host,
+            LoadedCert {
+                source,
+                modified,
+                certified,
+            },
+        );
+        Ok(())
+    }
+
+    //removing the default host makes one of the remaining hosts the default
+    pub fn remove(&self, host: &str) {
+        let host = host.to_ascii_lowercase();
+        let mut default = self.default.write().unwrap_or_else(|e| e.into_inner());
+        self.certs.remove(&host);
+        if default.as_deref() == Some(host.as_str()) {
+            *default = self.certs.iter().next().map(|entry| entry.key().clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    //reloads the certificates whose files changed, a certificate failing to load is kept as is
+    //until its files are fixed, returns the number of certificates reloaded
+    pub fn reload(&self) -> usize {
+        let mut reloaded = 0;
+        for mut entry in self.certs.iter_mut() {
+            let modified = entry.source.modified();
+            if modified.is_none() || modified == entry.modified {
+                continue;
+            }
+            entry.modified = modified;
+            match entry.source.load() {
+                Ok(certified) => {
+                    entry.certified = certified;
+                    reloaded += 1;
+                    info!(tenant = %entry.key(), "Certificate reloaded");
+                }
+                Err(e) => warn!(tenant = %entry.key(), "Fail to reload certificate: {:#}", e),
+            }
+        }
+        reloaded
+    }
+
+    fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = match server_name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => self
+                .default
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()?,
+        };
+        //a certificate registered for `*.example.com` covers `www.example.com`
+        let cert = self.certs.get(&host).or_else(|| {
+            let (_, parent) = host.split_once('.')?;
+            self.certs.get(&format!("*.{}", parent))
+        })?;
+        Some(cert.certified.clone())
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.get(client_hello.server_name());
+        if key.is_none() {
+            warn!(
+                "No certificate for server name {:?}",
+                client_hello.server_name()
+            );
+        }
+        key
+    }
+}
+
+//serves the app over https until `graceful` resolves, then waits for the open connections
+pub(crate) async fn serve(
+    port: u16,
+    app: Router,
+    certs: Arc<CertStore>,
+    options: TlsOptions,
+    drain: Drain,
+    graceful: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let config = server_config(certs.clone()).map_err(std::io::Error::other)?;
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            graceful.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    let reloader = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(options.reload_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            certs.reload();
+        }
+    });
+    if let Some(redirect_port) = options.redirect_port {
+        let listener = TcpListener::bind(("0.0.0.0", redirect_port)).await?;
+        info!("Redirecting http on port {} to https", redirect_port);
+        let redirect = Router::new().fallback(redirect).with_state(port);
+        tokio::spawn(async move {
+            let ret = axum::serve(listener, redirect)
+                .with_graceful_shutdown(async move {
+                    drain.started().await;
+                })
+                .await;
+            if let Err(e) = ret {
+                warn!("Http redirect listener stopped: {}", e);
+            }
+        });
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let ret = axum_server::bind_rustls(addr, RustlsConfig::from_config(Arc::new(config)))
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await;
+    reloader.abort();
+    ret
+}
+
+fn server_config(certs: Arc<CertStore>) -> Result<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider().clone()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+//rustls is built with ring only, chosen explicitly so it doesn't depend on a process default
+fn provider() -> &'static CryptoProvider {
+    static PROVIDER: OnceLock<CryptoProvider> = OnceLock::new();
+    PROVIDER.get_or_init(ring::default_provider)
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    File::open(path).with_context(|| format!("Fail to open {}", path.display()))
+}
+
+async fn redirect(State(port): State<u16>, Host(host): Host, uri: Uri) -> Redirect {
+    Redirect::permanent(&https_uri(&host, port, &uri))
+}
+
+fn https_uri(host: &str, port: u16, uri: &Uri) -> String {
+    //drop the port of the http listener, ipv6 hosts are kept in brackets
+    let host = match host.rsplit_once(':') {
+        Some((name, p)) if !p.contains(']') && p.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    match port {
+        443 => format!("https://{}{}", host, path),
+        _ => format!("https://{}:{}{}", host, port, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    fn write_cert(dir: &Path, name: &str, host: &str) -> TlsCert {
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let source = TlsCert::new(
+            dir.join(format!("{}.pem", name)),
+            dir.join(format!("{}-key.pem", name)),
+        );
+        fs::write(&source.cert, cert.cert.pem()).unwrap();
+        fs::write(&source.key, cert.key_pair.serialize_pem()).unwrap();
+        source
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dino-tls-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn cert_store_should_resolve_by_server_name() {
+        let dir = temp_dir("resolve");
+        let store = CertStore::default();
+        store
+            .insert("Localhost", write_cert(&dir, "localhost", "localhost"))
+            .unwrap();
+        store
+            .insert("example.com", write_cert(&dir, "example", "example.com"))
+            .unwrap();
+
+        let localhost = store.get(Some("localhost")).unwrap();
+        let example = store.get(Some("EXAMPLE.com")).unwrap();
+        assert_ne!(localhost.cert, example.cert);
+        assert!(store.get(Some("unknown.com")).is_none());
+        store
+            .insert("*.blog.com", write_cert(&dir, "blog", "*.blog.com"))
+            .unwrap();
+        assert!(store.get(Some("alice.blog.com")).is_some());
+        assert!(store.get(Some("blog.com")).is_none());
+        //no SNI falls back to the first host
+        assert_eq!(store.get(None).unwrap().cert, localhost.cert);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cert_store_should_move_default_on_remove() {
+        let dir = temp_dir("default");
+        let store = CertStore::default();
+        store
+            .insert("a.com", write_cert(&dir, "a", "a.com"))
+            .unwrap();
+        store
+            .insert("b.com", write_cert(&dir, "b", "b.com"))
+            .unwrap();
+        let b = store.get(Some("b.com")).unwrap();
+
+        store.remove("A.com");
+        assert_eq!(store.get(None).unwrap().cert, b.cert);
+        store.remove("b.com");
+        assert!(store.get(None).is_none());
+        //the next host inserted becomes the default
+        store
+            .insert("a.com", write_cert(&dir, "a", "a.com"))
+            .unwrap();
+        assert!(store.get(None).is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cert_store_should_reload_changed_files() {
+        let dir = temp_dir("reload");
+        let store = CertStore::default();
+        let source = write_cert(&dir, "localhost", "localhost");
+        store.insert("localhost", source.clone()).unwrap();
+        let before = store.get(Some("localhost")).unwrap();
+        assert_eq!(store.reload(), 0);
+
+        //a broken file keeps the certificate in use
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(&source.key, "not a key").unwrap();
+        assert_eq!(store.reload(), 0);
+        assert_eq!(store.get(Some("localhost")).unwrap().cert, before.cert);
+
+        std::thread::sleep(Duration::from_millis(20));
+        write_cert(&dir, "localhost", "localhost");
+        assert_eq!(store.reload(), 1);
+        assert_ne!(store.get(Some("localhost")).unwrap().cert, before.cert);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cert_store_should_reject_mismatched_key() {
+        let dir = temp_dir("mismatch");
+        let a = write_cert(&dir, "a", "a.com");
+        let b = write_cert(&dir, "b", "b.com");
+        let store = CertStore::default();
+        assert!(store.insert("a.com", TlsCert::new(a.cert, b.key)).is_err());
+        assert!(store.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn https_uri_should_work() {
+        let uri: Uri = "/api/hello/1?x=1".parse().unwrap();
+        assert_eq!(
+            https_uri("localhost:8080", 8443, &uri),
+            "https://localhost:8443/api/hello/1?x=1"
+        );
+        assert_eq!(
+            https_uri("example.com", 443, &uri),
+            "https://example.com/api/hello/1?x=1"
+        );
+        assert_eq!(
+            https_uri("[::1]:80", 443, &"/".parse().unwrap()),
+            "https://[::1]/"
+        );
+        assert_eq!(
+            https_uri("[::1]", 443, &uri),
+            "https://[::1]/api/hello/1?x=1"
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc, time::Duration};
+
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+use futures_util::{SinkExt as _, StreamExt as _};
+use rquickjs::{
+    function::{Rest, This},
+    Array, Ctx, FromJs, Function, IntoJs, Object, Value,
+};
+use tokio::sync::{mpsc, Notify};
+use tracing::{warn, Instrument as _, Span};
+
+use crate::{
+    stream::{js_error_message, Streams},
+    JsBody, Res, ResBody,
+};
+
+//number of messages buffered in each direction of a websocket
+const SOCKET_BUFFER: usize = 64;
+
+//wraps the native send and close into the socket object given to the websocket handlers
+const SOCKET: &str = r#"
+(function (send, close) {
+    let closed = false;
+    const socket = {
+        get closed() {
+            return closed;
+        },
+        send(data) {
+            if (closed) {
+                return false;
+            }
+            if (typeof data !== "string" && !(data instanceof Uint8Array) && !(data instanceof ArrayBuffer)) {
+                data = JSON.stringify(data);
+            }
+            return send(data);
+        },
+        close(code, reason) {
+            close(code ?? 1000, reason ?? "");
+        },
+    };
+    return [socket, () => { closed = true; }];
+})
+"#;
+
+//both ends of a websocket between the js engine and the connection
+pub struct WsChannel {
+    pub(crate) incoming: mpsc::Sender<Message>,
+    pub(crate) outgoing: mpsc::Receiver<Message>,
+}
+
+//native side of a socket, the sender is dropped once the socket is closed
+#[derive(Default)]
+struct SocketState {
+    tx: RefCell<Option<mpsc::Sender<Message>>>,
+    //the close frame given by the js, sent to the client before the connection ends
+    frame: RefCell<Option<CloseFrame<'static>>>,
+    close: Notify,
+}
+
+//how the socket ended, a local close sends its frame to the client
+enum Closed {
+    Local(CloseFrame<'static>),
+    Remote(Option<CloseFrame<'static>>),
+}
+
+//answers the upgrade request, the socket opened by the js is bridged to the connection
+pub(crate) fn upgrade(ws: WebSocketUpgrade, res: Res) -> Response {
+    match res.body {
+        Some(ResBody::Socket(channel)) => ws.on_upgrade(move |socket| bridge(socket, channel)),
+        body => Response::from(Res { body, ..res }),
+    }
+}
+
+async fn bridge(socket: WebSocket, channel: WsChannel) {
+    let (mut sink, mut stream) = socket.split();
+    let WsChannel {
+        incoming,
+        mut outgoing,
+    } = channel;
+    let recv = async move {
+        while let Some(Ok(msg)) = stream.next().await {
+            let close = matches!(msg, Message::Close(_));
+            if incoming.send(msg).await.is_err() || close {
+                break;
+            }
+        }
+    };
+    let send = async move {
+        while let Some(msg) = outgoing.recv().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    };
+    //whichever side ends first ends the connection
+    tokio::select! {
+        _ = recv => {}
+        _ = send => {}
+    }
+}
+
+//calls `onOpen` of the handler with a new socket, the socket then lives on the engine until
+//either side closes it or the engine shuts down, `onMessage` and `onClose` are called meanwhile
+pub(crate) async fn open_socket<'js>(
+    ctx: &Ctx<'js>,
+    handler: Object<'js>,
+    req: Object<'js>,
+) -> rquickjs::Result<WsChannel> {
+    let streams = Streams::from_ctx(ctx)?;
+    let on_open: Option<Function> = handler.get("onOpen")?;
+    let on_message: Option<Function> = handler.get("onMessage")?;
+    let on_close: Option<Function> = handler.get("onClose")?;
+
+    let (incoming, incoming_rx) = mpsc::channel(SOCKET_BUFFER);
+    let (outgoing_tx, outgoing) = mpsc::channel(SOCKET_BUFFER);
+    let state = Rc::new(SocketState::default());
+    state.tx.replace(Some(outgoing_tx));
+
+    let sender = state.clone();
+    let send = Function::new(ctx.clone(), move |ctx: Ctx<'js>, data: Value<'js>| {
+        let Some(tx) = sender.tx.borrow().clone() else {
+            return Ok::<_, rquickjs::Error>(false);
+        };
+        let msg = match data.as_string() {
+            Some(text) => Message::Text(text.to_string()?),
+            None => Message::Binary(JsBody::from_js(&ctx, data)?.0.into()),
+        };
+        match tx.try_send(msg) {
+            Ok(()) => Ok(true),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Websocket client is too slow, dropping message");
+                Ok(false)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Ok(false),
+        }
+    })?;
+    let closer = state.clone();
+    let close = Function::new(ctx.clone(), move |code: u16, reason: String| {
+        closer.frame.replace(Some(CloseFrame {
+            code,
+            reason: Cow::Owned(reason),
+        }));
+        closer.close.notify_one();
+    })?;
+
+    let wrap: Function = ctx.eval(SOCKET)?;
+    let pair: Array = wrap.call((send, close))?;
+    let (socket, mark_closed): (Object, Function) = (pair.get(0)?, pair.get(1)?);
+
+    //a failing onOpen rejects the upgrade
+    if let Some(on_open) = on_open {
+        call(
+            &on_open,
+            &socket,
+            vec![socket.clone().into_value(), req.into_value()],
+        )
+        .await?;
+    }
+
+    let timeout = streams.timeout();
+    streams.begin();
+    let inner = ctx.clone();
+    ctx.spawn(
+        async move {
+            let session = Session {
+                ctx: inner,
+                streams: streams.clone(),
+                timeout,
+                state,
+                socket,
+            };
+            let closed = session.receive(on_message, incoming_rx).await;
+            session.close(closed, mark_closed, on_close).await;
+            streams.end();
+        }
+        .instrument(Span::current()),
+    );
+    Ok(WsChannel { incoming, outgoing })
+}
+
+struct Session<'js> {
+    ctx: Ctx<'js>,
+    streams: Streams,
+    //of the handler that opened the socket, for each onMessage and onClose call
+    timeout: Duration,
+    state: Rc<SocketState>,
+    socket: Object<'js>,
+}
+
+impl<'js> Session<'js> {
+    async fn receive(
+        &self,
+        on_message: Option<Function<'js>>,
+        mut incoming: mpsc::Receiver<Message>,
+    ) -> Closed {
+        loop {
+            let msg = tokio::select! {
+                msg = incoming.recv() => msg,
+                _ = self.state.close.notified() => {
+                    let frame = self.state.frame.take().unwrap_or_else(|| frame(close_code::NORMAL, ""));
+                    return Closed::Local(frame);
+                }
+                _ = self.streams.closed() => {
+                    return Closed::Local(frame(close_code::AWAY, "server is shutting down"));
+                }
+            };
+            let data = match msg {
+                Some(Message::Text(text)) => text.into_js(&self.ctx),
+                Some(Message::Binary(bytes)) => JsBody::from(bytes).into_js(&self.ctx),
+                Some(Message::Close(frame)) => return Closed::Remote(frame),
+                Some(_) => continue,
+                //the client went away without a close frame
+                None => return Closed::Remote(None),
+            };
+            let Some(on_message) = &on_message else {
+                continue;
+            };
+            let ret = match data {
+                Ok(data) => {
+                    let args = vec![self.socket.clone().into_value(), data];
+                    self.streams
+                        .guard(self.timeout, call(on_message, &self.socket, args))
+                        .await
+                }
+                Err(e) => Ok(Err(e)),
+            };
+            match ret {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!(
+                        "Websocket onMessage failed: {}",
+                        js_error_message(&self.ctx, e)
+                    );
+                    return Closed::Local(frame(close_code::ERROR, "internal error"));
+                }
+                Err(timeout) => {
+                    warn!("Websocket onMessage timed out after {:?}", timeout);
+                    return Closed::Local(frame(close_code::ERROR, "internal error"));
+                }
+            }
+        }
+    }
+
+    async fn close(
+        &self,
+        closed: Closed,
+        mark_closed: Function<'js>,
+        on_close: Option<Function<'js>>,
+    ) {
+        let tx = self.state.tx.take();
+        if let Err(e) = mark_closed.call::<_, Value>(()) {
+            warn!(
+                "Fail to close websocket: {}",
+                js_error_message(&self.ctx, e)
+            );
+        }
+        let (code, reason) = match closed {
+            Closed::Local(frame) => {
+                let ret = (frame.code, frame.reason.to_string());
+                if let Some(tx) = tx {
+                    let _ = tx.send(Message::Close(Some(frame))).await;
+                }
+                ret
+            }
+            Closed::Remote(Some(frame)) => (frame.code, frame.reason.to_string()),
+            Closed::Remote(None) => (close_code::STATUS, String::new()),
+        };
+        let Some(on_close) = on_close else {
+            return;
+        };
+        let args = match (code.into_js(&self.ctx), reason.into_js(&self.ctx)) {
+            (Ok(code), Ok(reason)) => vec![self.socket.clone().into_value(), code, reason],
+            _ => vec![self.socket.clone().into_value()],
+        };
+        match self
+            .streams
+            .guard(self.timeout, call(&on_close, &self.socket, args))
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(
+                "Websocket onClose failed: {}",
+                js_error_message(&self.ctx, e)
+            ),
+            Err(timeout) => warn!("Websocket onClose timed out after {:?}", timeout),
+        }
+    }
+}
+
+fn frame(code: u16, reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: Cow::Borrowed(reason),
+    }
+}
+
+//the handlers are called with the socket as `this` and as first argument, a promise is awaited
+async fn call<'js>(
+    function: &Function<'js>,
+    socket: &Object<'js>,
+    args: Vec<Value<'js>>,
+) -> rquickjs::Result<()> {
+    let ret: Value = function.call((This(socket.clone()), Rest(args)))?;
+    if let Some(promise) = ret.into_promise() {
+        promise.into_future::<Value>().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{JsEngine, Req};
+
+    const CODE: &str = r#"
+        (function(){
+            const chat = {
+                onOpen(socket, req) {
+                    socket.send("welcome " + req.params.room);
+                },
+                async onMessage(socket, data) {
+                    if (data === "bye") {
+                        socket.close(4000, "see you");
+                    } else if (typeof data === "string") {
+                        socket.send(data.toUpperCase());
+                    } else {
+                        socket.send({ bytes: data.length });
+                    }
+                },
+                onClose(socket, code, reason) {
+                    globalThis.closed = [code, reason, socket.closed, socket.send("late")];
+                },
+            };
+            const rejected = {
+                onOpen(socket, req) {
+                    throw new Error("not allowed");
+                },
+            };
+            async function closed(req){
+                return { status: 200, headers: {}, body: JSON.stringify(globalThis.closed) };
+            }
+            return { chat, rejected, closed };
+        })();
+    "#;
+
+    fn req() -> Req {
+        Req::builder()
+            .method("GET")
+            .url("/ws/general")
+            .params([("room".to_string(), "general".to_string())].into())
+            .build()
+    }
+
+    async fn open(engine: &JsEngine, name: &str) -> Result<WsChannel, crate::AppError> {
+        let res = engine.run_ws(name, req(), Duration::from_secs(1)).await?;
+        let Some(ResBody::Socket(channel)) = res.body else {
+            panic!("websocket handler should open a socket");
+        };
+        Ok(channel)
+    }
+
+    #[tokio::test]
+    async fn websocket_handlers_should_work() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let WsChannel {
+            incoming,
+            mut outgoing,
+        } = open(&engine, "chat").await.unwrap();
+        let client = async move {
+            let mut received = vec![outgoing.recv().await];
+            incoming.send(Message::Text("hi".into())).await.unwrap();
+            received.push(outgoing.recv().await);
+            incoming.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+            received.push(outgoing.recv().await);
+            incoming.send(Message::Text("bye".into())).await.unwrap();
+            received.push(outgoing.recv().await);
+            received.push(outgoing.recv().await);
+            received
+        };
+        let (_, received) = tokio::join!(engine.drain_streams(), client);
+        assert_eq!(
+            received,
+            [
+                Some(Message::Text("welcome general".into())),
+                Some(Message::Text("HI".into())),
+                Some(Message::Text("{\"bytes\":3}".into())),
+                Some(Message::Close(Some(frame(4000, "see you")))),
+                None,
+            ]
+        );
+
+        let res = engine
+            .run("closed", req(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.body, Some("[4000,\"see you\",true,false]".into()));
+    }
+
+    #[tokio::test]
+    async fn websocket_client_close_should_call_on_close() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        let channel = open(&engine, "chat").await.unwrap();
+        drop(channel);
+        engine.drain_streams().await;
+        let res = engine
+            .run("closed", req(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(res.body, Some("[1005,\"\",true,false]".into()));
+    }
+
+    #[tokio::test]
+    async fn websocket_failing_on_open_should_reject() {
+        let engine = JsEngine::new(CODE, &Default::default()).await.unwrap();
+        assert!(open(&engine, "rejected").await.is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::Args;
+use dino_server::{default_redacted_headers, AccessLogOptions, AccessLogTarget};
+
+//access log flags shared by run and serve
+#[derive(Debug, Args)]
+pub struct AccessLogArgs {
+    //write one json line per request to this file, `-` for stdout
+    #[arg(long)]
+    pub access_log: Option<String>,
+    //rotate the file once it reaches this many megabytes
+    #[arg(long, default_value = "100")]
+    pub access_log_max_size: u64,
+    //also rotate the file after this many hours
+    #[arg(long)]
+    pub access_log_max_age: Option<u64>,
+    //rotated files kept, older ones are deleted
+    #[arg(long, default_value = "7")]
+    pub access_log_max_files: usize,
+    //header whose value is redacted in the access log, on top of authorization and cookies
+    #[arg(long = "redact-header")]
+    pub redact_headers: Vec<String>,
+}
+
+impl AccessLogArgs {
+    pub fn options(&self) -> Option<AccessLogOptions> {
+        let target = match self.access_log.as_deref()? {
+            "-" => AccessLogTarget::Stdout,
+            path => AccessLogTarget::File(PathBuf::from(path)),
+        };
+        let mut redact_headers = default_redacted_headers();
+        redact_headers.extend(self.redact_headers.iter().cloned());
+        let options = AccessLogOptions::builder()
+            .target(target)
+            .max_size(self.access_log_max_size * 1024 * 1024)
+            .max_age(
+                self.access_log_max_age
+                    .map(|hours| Duration::from_secs(hours * 3600)),
+            )
+            .max_files(self.access_log_max_files)
+            .redact_headers(redact_headers)
+            .build();
+        Some(options)
+    }
+}
@@ -1,11 +1,20 @@
+mod access_log;
 mod build;
 mod init;
 mod run;
+mod serve;
+pub use access_log::AccessLogArgs;
 pub use build::BuildOpts;
-use clap::{command, Parser};
+use clap::Parser;
+use dino_server::Telemetry;
 use enum_dispatch::enum_dispatch;
 pub use init::InitOpts;
 pub use run::RunOpts;
+pub use serve::ServeOpts;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "dino", version, author, about,long_about=None)]
@@ -23,4 +32,17 @@ pub enum Subcommand {
     Build(BuildOpts),
     #[command(name = "run", about = "Run user's project")]
     Run(RunOpts),
+    #[command(name = "serve", about = "Serve every project of a directory")]
+    Serve(ServeOpts),
+}
+
+//pretty logs on stdout, plus the spans exported over otlp when an endpoint is given
+pub(crate) fn init_tracing(otlp: Option<&str>) -> anyhow::Result<Option<Telemetry>> {
+    let telemetry = otlp.map(Telemetry::otlp).transpose()?;
+    let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
+    let otel = telemetry
+        .as_ref()
+        .map(|t| t.layer().with_filter(LevelFilter::INFO));
+    tracing_subscriber::registry().with(layer).with(otel).init();
+    Ok(telemetry)
 }
@@ -1,41 +1,145 @@
 use clap::Parser;
-use dino_server::{start_server, ProjectConfig, SwappableAppRouter, TenentRouter};
+use dino_server::{
+    start_server, AdminOptions, ErrorDetail, ProjectConfig, ServerOptions, SwappableAppRouter,
+    TenentRouter, TlsCert, TlsOptions, DEFAULT_OTLP_ENDPOINT,
+};
 use notify::RecursiveMode;
 use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
 use std::{fs, path::Path, time::Duration};
 use tokio::sync::mpsc::channel;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt as _};
 
-use tracing::{info, level_filters::LevelFilter, warn};
-use tracing_subscriber::{
-    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
-};
+use tracing::{info, warn};
 
-use crate::{build_project, CmdExcetor};
+use super::{init_tracing, AccessLogArgs};
+use crate::{build_project, load_bundle, CmdExcetor, BUILD_DIR};
+
+const ADMIN_TOKEN_ENV: &str = "DINO_ADMIN_TOKEN";
 
 #[derive(Debug, Parser)]
 
 pub struct RunOpts {
     #[arg(short, long, default_value = "8080")]
     pub port: u16,
+    //seconds given to in-flight requests and shutdown hooks on SIGTERM or ctrl-c
+    #[arg(long, default_value = "30")]
+    pub drain_timeout: u64,
+    //serve https with a self-signed certificate for localhost
+    #[arg(long)]
+    pub https: bool,
+    //plain http port redirecting to https, only used with --https
+    #[arg(long, requires = "https")]
+    pub redirect_port: Option<u16>,
+    //port of the admin api on loopback, the token is read from DINO_ADMIN_TOKEN
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+    //also serve the prometheus metrics on this path of the main port, e.g. `/metrics`
+    #[arg(long)]
+    pub metrics_path: Option<String>,
+    //send the error messages and js stacks in the problem+json of the errors, for development
+    #[arg(long)]
+    pub verbose_errors: bool,
+    //export the spans to an otlp collector, `--otlp` alone uses the one on localhost:4317
+    #[arg(long, num_args = 0..=1, default_missing_value = DEFAULT_OTLP_ENDPOINT)]
+    pub otlp: Option<String>,
+    #[command(flatten)]
+    pub access_log: AccessLogArgs,
 }
 
 impl CmdExcetor for RunOpts {
     async fn execute(self) -> anyhow::Result<()> {
-        let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
-        tracing_subscriber::registry().with(layer).init();
-        let filename = build_project(".")?;
-        let code = fs::read_to_string(&filename)?;
+        let telemetry = init_tracing(self.otlp.as_deref())?;
+        let filename = tokio::task::spawn_blocking(|| build_project(".")).await??;
+        let bundle = load_bundle(&filename)?;
         let config = ProjectConfig::load(filename.replace(".mjs", ".yml"))?;
-        let router = SwappableAppRouter::new(code.to_string(), config.routes)?;
-        let routers = vec![TenentRouter::new("localhost".to_string(), router.clone())];
+        //the only project answers whatever host it is reached by
+        let mut hosts = config.hosts.clone();
+        if hosts.is_empty() {
+            hosts.push("localhost".to_string());
+        }
+        bundle.check(&config).await?;
+        let router = SwappableAppRouter::new(bundle, config)?;
+        let mut tls = None;
+        let cert = match self.https {
+            true => {
+                tls = Some(
+                    TlsOptions::builder()
+                        .redirect_port(self.redirect_port)
+                        .build(),
+                );
+                Some(self_signed_cert(&hosts)?)
+            }
+            false => None,
+        };
+        let routers = hosts
+            .iter()
+            .map(|host| {
+                let tenant = TenentRouter::new(host.clone(), router.clone());
+                match &cert {
+                    Some(cert) => tenant.with_tls(cert.clone()),
+                    None => tenant,
+                }
+            })
+            .collect();
+        let admin = match self.admin_port {
+            Some(port) => {
+                let token = std::env::var(ADMIN_TOKEN_ENV).map_err(|_| {
+                    anyhow::anyhow!("{} must be set to use --admin-port", ADMIN_TOKEN_ENV)
+                })?;
+                Some(AdminOptions::builder().port(port).token(token).build())
+            }
+            None => None,
+        };
         tokio::spawn(async_watch(".", router));
-        start_server(self.port, routers).await?;
+        let options = ServerOptions::builder()
+            .drain_timeout(Duration::from_secs(self.drain_timeout))
+            .tls(tls)
+            .admin(admin)
+            .fallback(hosts[0].clone())
+            .access_log(self.access_log.options())
+            .metrics_path(self.metrics_path)
+            .error_detail(if self.verbose_errors {
+                ErrorDetail::Verbose
+            } else {
+                ErrorDetail::Prod
+            })
+            .build();
+        let ret = start_server(self.port, routers, options).await;
+        if let Some(telemetry) = telemetry {
+            telemetry.shutdown().await;
+        }
+        ret?;
 
         Ok(())
     }
 }
 
+//certificate for local development, generated once and kept in the build directory so the
+//browser exception survives restarts, delete it to cover hosts added since
+fn self_signed_cert(hosts: &[String]) -> anyhow::Result<TlsCert> {
+    let tls = TlsCert::new(
+        format!("{}/localhost.pem", BUILD_DIR),
+        format!("{}/localhost-key.pem", BUILD_DIR),
+    );
+    if tls.cert.exists() && tls.key.exists() {
+        return Ok(tls);
+    }
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    names.extend(
+        hosts
+            .iter()
+            .filter(|h| !names.contains(h))
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    fs::create_dir_all(BUILD_DIR)?;
+    fs::write(&tls.cert, cert.cert.pem())?;
+    fs::write(&tls.key, cert.key_pair.serialize_pem())?;
+    info!("Generated self-signed certificate {}", tls.cert.display());
+    Ok(tls)
+}
+
 async fn async_watch(path: impl AsRef<Path>, router: SwappableAppRouter) -> anyhow::Result<()> {
     let (tx, rx) = channel(1);
     let mut debouncer = new_debouncer(Duration::from_secs(1), move |res: DebounceEventResult| {
@@ -68,12 +172,14 @@ async fn async_watch(path: impl AsRef<Path>, router: SwappableAppRouter) -> anyh
                         break;
                     }
                 }
+                //a broken edit keeps the previous build served, the next one is tried again
                 if need_swap {
-                    let filename = build_project(".")?;
-                    let config = filename.replace(".mjs", ".yml");
-                    let code = fs::read_to_string(&filename)?;
-                    let config = ProjectConfig::load(config)?;
-                    router.swap(code, config.routes)?;
+                    if let Err(e) = reload(&router).await {
+                        warn!(
+                            "Fail to reload project, the previous build is kept: {:#}",
+                            e
+                        );
+                    }
                 }
             }
             Err(e) => {
@@ -83,3 +189,14 @@ async fn async_watch(path: impl AsRef<Path>, router: SwappableAppRouter) -> anyh
     }
     Ok(())
 }
+
+async fn reload(router: &SwappableAppRouter) -> anyhow::Result<()> {
+    //swc is synchronous, it builds on a blocking thread so the server keeps answering
+    let filename = tokio::task::spawn_blocking(|| build_project(".")).await??;
+    let config = filename.replace(".mjs", ".yml");
+    let bundle = load_bundle(&filename)?;
+    let config = ProjectConfig::load(config)?;
+    bundle.check(&config).await?;
+    router.swap(bundle, config)?;
+    Ok(())
+}
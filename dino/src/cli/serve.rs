@@ -0,0 +1,312 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use dino_server::{
+    serve, AppState, ErrorDetail, ProjectConfig, ServerOptions, SwappableAppRouter,
+    DEFAULT_OTLP_ENDPOINT,
+};
+use notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use tokio::sync::mpsc::channel;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt as _};
+use tracing::{info, warn};
+
+use super::{init_tracing, AccessLogArgs};
+use crate::{build_project, load_bundle, CmdExcetor, BUILD_DIR};
+
+#[derive(Debug, Parser)]
+pub struct ServeOpts {
+    //directory holding one project per subdirectory
+    #[arg(long, default_value = ".")]
+    pub projects: PathBuf,
+    #[arg(short, long, default_value = "8080")]
+    pub port: u16,
+    //seconds given to in-flight requests and shutdown hooks on SIGTERM or ctrl-c
+    #[arg(long, default_value = "30")]
+    pub drain_timeout: u64,
+    //host of the project answering requests no project claims
+    #[arg(long)]
+    pub fallback: Option<String>,
+    //also serve each project under `<prefix>/<host>/...`, e.g. `--mount-prefix /t`
+    #[arg(long)]
+    pub mount_prefix: Option<String>,
+    //also serve the prometheus metrics on this path of the main port, e.g. `/metrics`
+    #[arg(long)]
+    pub metrics_path: Option<String>,
+    //send the error messages and js stacks in the problem+json of the errors, for development
+    #[arg(long)]
+    pub verbose_errors: bool,
+    //export the spans to an otlp collector, `--otlp` alone uses the one on localhost:4317
+    #[arg(long, num_args = 0..=1, default_missing_value = DEFAULT_OTLP_ENDPOINT)]
+    pub otlp: Option<String>,
+    #[command(flatten)]
+    pub access_log: AccessLogArgs,
+}
+
+//projects currently registered, by directory
+#[derive(Default)]
+struct Projects {
+    state: AppState,
+    registered: HashMap<PathBuf, Project>,
+}
+
+struct Project {
+    router: SwappableAppRouter,
+    hosts: Vec<String>,
+    //output of the build being served
+    build: String,
+}
+
+impl CmdExcetor for ServeOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        let telemetry = init_tracing(self.otlp.as_deref())?;
+        let root = self.projects.canonicalize()?;
+        let mut projects = Projects::default();
+        for dir in project_dirs(&root)? {
+            if let Err(e) = projects.deploy(&dir).await {
+                warn!("Fail to serve project {}: {:#}", dir.display(), e);
+            }
+        }
+        let state = projects.state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = async_watch(root, projects).await {
+                warn!("Stopped watching projects: {:#}", e);
+            }
+        });
+        let options = ServerOptions::builder()
+            .drain_timeout(Duration::from_secs(self.drain_timeout))
+            .fallback(self.fallback)
+            .mount_prefix(self.mount_prefix)
+            .access_log(self.access_log.options())
+            .metrics_path(self.metrics_path)
+            .error_detail(if self.verbose_errors {
+                ErrorDetail::Verbose
+            } else {
+                ErrorDetail::Prod
+            })
+            .build();
+        let ret = serve(self.port, state, options).await;
+        if let Some(telemetry) = telemetry {
+            telemetry.shutdown().await;
+        }
+        ret?;
+        Ok(())
+    }
+}
+
+impl Projects {
+    //builds the project and registers it under its hosts, or swaps it if already registered,
+    //on failure the version being served is kept
+    async fn deploy(&mut self, dir: &Path) -> Result<()> {
+        //swc is synchronous, it builds on a blocking thread so the server keeps answering
+        let project_dir = dir.to_string_lossy().into_owned();
+        let filename = tokio::task::spawn_blocking(move || build_project(&project_dir)).await??;
+        //touching the project without changing its sources gives the same build
+        if self
+            .registered
+            .get(dir)
+            .is_some_and(|project| project.build == filename)
+        {
+            return Ok(());
+        }
+        let bundle = load_bundle(&filename)?;
+        let config = ProjectConfig::load(filename.replace(".mjs", ".yml"))?;
+        let hosts = config.hosts.clone();
+        if hosts.is_empty() {
+            return Err(anyhow!("No hosts in config.yml, nothing to serve it on"));
+        }
+        for host in &hosts {
+            if let Some(owner) = self.owner(host).filter(|owner| *owner != dir) {
+                return Err(anyhow!(
+                    "Host {} is already served by {}",
+                    host,
+                    owner.display()
+                ));
+            }
+        }
+        bundle.check(&config).await?;
+
+        match self.registered.get_mut(dir) {
+            Some(project) => {
+                //the new hosts are claimed before the swap, and given back if it fails
+                let added: Vec<_> = hosts
+                    .iter()
+                    .filter(|h| !project.hosts.contains(h))
+                    .cloned()
+                    .collect();
+                register_all(&self.state, &added, &project.router)?;
+                if let Err(e) = project.router.swap(bundle, config) {
+                    unregister_all(&self.state, &added, &project.router);
+                    return Err(e);
+                }
+                for host in project.hosts.iter().filter(|h| !hosts.contains(h)) {
+                    self.state.remove(host);
+                }
+                project.hosts = hosts;
+                project.build = filename;
+                info!("Project {} swapped", dir.display());
+            }
+            None => {
+                let router = SwappableAppRouter::new(bundle, config)?;
+                register_all(&self.state, &hosts, &router)?;
+                info!("Project {} served on {}", dir.display(), hosts.join(", "));
+                let project = Project {
+                    router,
+                    hosts,
+                    build: filename,
+                };
+                self.registered.insert(dir.to_path_buf(), project);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, dir: &Path) {
+        if let Some(project) = self.registered.remove(dir) {
+            for host in &project.hosts {
+                self.state.remove(host);
+            }
+            info!("Project {} removed", dir.display());
+        }
+    }
+
+    fn owner(&self, host: &str) -> Option<&Path> {
+        self.registered
+            .iter()
+            .find(|(_, project)| project.hosts.iter().any(|h| h == host))
+            .map(|(dir, _)| dir.as_path())
+    }
+}
+
+//registers the router under every host, or under none of them if one is claimed by another tenant
+fn register_all(state: &AppState, hosts: &[String], router: &SwappableAppRouter) -> Result<()> {
+    for host in hosts {
+        if let Err(e) = state.tenants().register(host, router.clone()) {
+            unregister_all(state, hosts, router);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn unregister_all(state: &AppState, hosts: &[String], router: &SwappableAppRouter) {
+    for host in hosts {
+        state.tenants().unregister(host, router);
+    }
+}
+
+//subdirectories with a config.yml, hidden ones are skipped
+fn project_dirs(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut dirs = BTreeSet::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if is_project(&path) {
+            dirs.insert(path);
+        }
+    }
+    Ok(dirs)
+}
+
+fn is_project(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    !hidden && path.join("config.yml").is_file()
+}
+
+//the project a changed file belongs to, changes in build output are ignored
+fn project_of(root: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut components = relative.components();
+    let Some(Component::Normal(project)) = components.next() else {
+        return None;
+    };
+    if components.any(|c| c.as_os_str() == BUILD_DIR) {
+        return None;
+    }
+    Some(root.join(project))
+}
+
+async fn async_watch(root: PathBuf, mut projects: Projects) -> Result<()> {
+    let (tx, rx) = channel(1);
+    let mut debouncer = new_debouncer(Duration::from_secs(1), move |res: DebounceEventResult| {
+        if let Err(e) = tx.blocking_send(res) {
+            warn!("Fail to send event:{}", e);
+        }
+    })?;
+    debouncer.watcher().watch(&root, RecursiveMode::Recursive)?;
+
+    let mut stream = ReceiverStream::new(rx);
+    while let Some(ret) = stream.next().await {
+        let events = match ret {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Fail to get event:{}", e);
+                continue;
+            }
+        };
+        let changed: BTreeSet<_> = events
+            .iter()
+            .filter_map(|event| project_of(&root, &event.path))
+            .collect();
+        for dir in changed {
+            if is_project(&dir) {
+                if let Err(e) = projects.deploy(&dir).await {
+                    warn!("Fail to serve project {}: {:#}", dir.display(), e);
+                }
+            } else {
+                projects.remove(&dir);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_of_should_work() {
+        let root = Path::new("/srv/projects");
+        assert_eq!(
+            project_of(root, Path::new("/srv/projects/shop/lib/a.ts")),
+            Some(PathBuf::from("/srv/projects/shop"))
+        );
+        assert_eq!(
+            project_of(root, Path::new("/srv/projects/shop")),
+            Some(PathBuf::from("/srv/projects/shop"))
+        );
+        assert_eq!(
+            project_of(root, Path::new("/srv/projects/shop/.build/abc.yml")),
+            None
+        );
+        assert_eq!(project_of(root, Path::new("/srv/other/a.ts")), None);
+        assert_eq!(project_of(root, root), None);
+    }
+
+    #[test]
+    fn register_all_should_roll_back() {
+        let filename = std::env::temp_dir().join("dino-register-all.yml");
+        fs::write(&filename, "name: test\nroutes: {}\n").unwrap();
+        let router = || {
+            let config = ProjectConfig::load(&filename).unwrap();
+            SwappableAppRouter::new("(function(){ return {}; })();".to_string(), config).unwrap()
+        };
+        let (state, shop, blog) = (AppState::default(), router(), router());
+        state.tenants().register("blog.test", blog.clone()).unwrap();
+
+        let hosts = ["shop.test".to_string(), "blog.test".to_string()];
+        assert!(register_all(&state, &hosts, &shop).is_err());
+        assert!(state.tenants().get("shop.test").is_none());
+        assert!(state.tenants().get("blog.test").is_some());
+        register_all(&state, &hosts[..1], &shop).unwrap();
+        assert!(state.tenants().get("shop.test").is_some());
+    }
+}
@@ -2,7 +2,7 @@ use enum_dispatch::enum_dispatch;
 mod cli;
 
 mod utils;
-pub use cli::{BuildOpts, InitOpts, Opts, RunOpts, Subcommand};
+pub use cli::{AccessLogArgs, BuildOpts, InitOpts, Opts, RunOpts, ServeOpts, Subcommand};
 
 pub(crate) use utils::*;
 
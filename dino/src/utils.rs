@@ -6,6 +6,7 @@ use std::{
 
 use anyhow::Result;
 use bundler::run_bundle;
+use dino_server::JsBundle;
 use glob::glob;
 
 use crate::BUILD_DIR;
@@ -27,8 +28,9 @@ pub(crate) fn get_files_with_extension(dir: &str, ext: &[&str]) -> Result<BTreeS
     // println!("files: {:?}", files);
     Ok(files)
 }
+//config.yml is part of the build, a change to it alone must produce a new one
 pub(crate) fn calc_project_hash(dir: &str) -> Result<String> {
-    let ext = [".ts", ".json", ".js"];
+    let ext = [".ts", ".json", ".js", ".yml"];
     calc_hash_for_files(dir, &ext, 12)
 }
 
@@ -44,10 +46,12 @@ pub(crate) fn calc_hash_for_files(dir: &str, ext: &[&str], len: usize) -> Result
     Ok(result)
 }
 
+//builds the project in `dir` into its own build directory
 pub(crate) fn build_project(dir: &str) -> Result<String> {
     let hash = calc_project_hash(dir)?;
-    let filename = format!("{}/{}.mjs", BUILD_DIR, hash);
-    let config = format!("{}/{}.yml", BUILD_DIR, hash);
+    let build_dir = Path::new(dir).join(BUILD_DIR);
+    let filename = format!("{}/{}.mjs", build_dir.display(), hash);
+    let config = format!("{}/{}.yml", build_dir.display(), hash);
 
     let dst = Path::new(&filename);
     if dst.exists() {
@@ -56,17 +60,26 @@ pub(crate) fn build_project(dir: &str) -> Result<String> {
     }
 
     // println!("Building project: {}", filename);
-    let content = run_bundle("main.ts", &Default::default())?;
+    let entry = Path::new(dir).join("main.ts");
+    let bundle = run_bundle(&entry.to_string_lossy(), &Default::default())?;
 
-    fs::create_dir_all(BUILD_DIR)?;
-    fs::write(dst, content)?;
+    fs::create_dir_all(&build_dir)?;
+    fs::write(dst, bundle.code)?;
+    fs::write(format!("{}.map", filename), bundle.source_map)?;
     let mut dst = File::create(config)?;
-    let mut src = File::open("config.yml")?;
+    let mut src = File::open(Path::new(dir).join("config.yml"))?;
     std::io::copy(&mut src, &mut dst)?;
 
     Ok(filename)
 }
 
+//the built code and the source map next to it, builds made before maps were written have none
+pub(crate) fn load_bundle(filename: &str) -> Result<JsBundle> {
+    let code = fs::read_to_string(filename)?;
+    let source_map = fs::read_to_string(format!("{}.map", filename)).ok();
+    Ok(JsBundle { code, source_map })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;